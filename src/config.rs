@@ -2,28 +2,242 @@ use anyhow::{anyhow, Context, Result};
 use std::{
     fs,
     net::{SocketAddr, ToSocketAddrs as _},
+    path::PathBuf,
     time,
 };
 
+use clap::ValueEnum;
 use reqwest::Url;
 use serde::Deserialize;
 
+use crate::ssdp::search::SearchHosts;
+use crate::ssdp::Interfaces;
 use crate::CommandLineConf;
 
+const DEFAULT_PERIOD: u64 = 895;
+const DEFAULT_DESCRIPTION_TTL: u64 = 300;
+const DEFAULT_BROWSE_CACHE: u64 = 16;
+const DEFAULT_IMAGE_CACHE_SIZE: u64 = 64;
+const DEFAULT_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+const DEFAULT_SEARCH_INTERVAL: u64 = 60;
+const DEFAULT_PEER_SEARCH_TARGET: &str = "upnp:rootdevice";
+
+/// How the proxy handles the traffic it relays.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyMode {
+    /// Relay bytes as is, as proxies always did.
+    #[default]
+    Tcp,
+    /// Parse HTTP and rewrite URLs pointing at the remote server.
+    Http,
+}
+
+#[derive(Deserialize)]
+struct RawServerConfig {
+    description_url: Option<String>,
+    discover: Option<String>,
+    period: Option<u64>,
+    description_ttl: Option<u64>,
+    proxy: Option<String>,
+    proxy_mode: Option<ProxyMode>,
+    friendly_name: Option<String>,
+    model_name: Option<String>,
+    virtual_udn: Option<bool>,
+    serve: Option<String>,
+    browse_cache: Option<u64>,
+    follow: Option<String>,
+}
+
+/// `iface = "eth0"` or `iface = ["eth0", "eth1"]`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(one) => vec![one],
+            OneOrMany::Many(many) => many,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawAggregateConfig {
+    bind: String,
+    friendly_name: Option<String>,
+    period: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawReverseConfig {
+    iface: Option<OneOrMany>,
+    proxy: String,
+    search_target: Option<String>,
+    search_interval: Option<u64>,
+    period: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawRelayConfig {
+    iface: Vec<String>,
+    #[serde(default)]
+    types: Vec<String>,
+    proxy: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPeerConfig {
+    listen: Option<String>,
+    connect: Option<String>,
+    secret: Option<String>,
+    iface: Option<OneOrMany>,
+    proxy: String,
+    search_target: Option<String>,
+    search_interval: Option<u64>,
+    period: Option<u64>,
+}
+
 #[derive(Deserialize)]
 struct RawConfig {
     description_url: Option<String>,
+    discover: Option<String>,
     period: Option<u64>,
+    description_ttl: Option<u64>,
     proxy: Option<String>,
+    proxy_mode: Option<ProxyMode>,
+    friendly_name: Option<String>,
+    model_name: Option<String>,
+    virtual_udn: Option<bool>,
+    serve: Option<String>,
+    browse_cache: Option<u64>,
+    follow: Option<String>,
     verbose: Option<u8>,
-    iface: Option<String>,
+    iface: Option<OneOrMany>,
+    ipv6: Option<bool>,
+    image_cache: Option<PathBuf>,
+    image_cache_size: Option<u64>,
+    #[serde(default)]
+    server: Vec<RawServerConfig>,
+    aggregate: Option<RawAggregateConfig>,
+    reverse: Option<RawReverseConfig>,
+    relay: Option<RawRelayConfig>,
+    peer: Option<RawPeerConfig>,
 }
 
-pub struct Config {
-    pub description_url: Url,
+/// Where the remote server's description is.
+#[derive(Clone)]
+pub enum RemoteDescription {
+    Url(Url),
+    /// Found with unicast M-SEARCHes, for when its port and path aren't known or change.
+    Discover(SearchHosts),
+}
+
+impl RemoteDescription {
+    /// The description URL, when known in advance.
+    pub fn url(&self) -> Option<&Url> {
+        match self {
+            RemoteDescription::Url(url) => Some(url),
+            RemoteDescription::Discover(_) => None,
+        }
+    }
+}
+
+pub struct ServerConfig {
+    pub description: RemoteDescription,
+    pub period: time::Duration,
+    pub description_ttl: time::Duration,
+    pub proxy: Option<SocketAddr>,
+    pub proxy_mode: ProxyMode,
+    /// Template for the name announced in the proxied description, e.g. `{friendlyName} (remote)`.
+    pub friendly_name: Option<String>,
+    pub model_name: Option<String>,
+    /// Announce the server under a UDN of its own rather than the remote one's.
+    pub virtual_udn: bool,
+    /// Where to serve our own description of the server, announced in place of the remote one.
+    pub serve: Option<SocketAddr>,
+    /// Size limit of the HTTP proxy's Browse/Search response cache, in bytes, 0 disabling it.
+    pub browse_cache_size: usize,
+    /// Interface on which the server's own announcements are heard, followed instead of checking on it every period.
+    pub follow: Option<String>,
+}
+
+/// All servers exposed as one, which dlnaproxy serves itself.
+pub struct AggregateConfig {
+    pub bind: SocketAddr,
+    pub friendly_name: String,
+    pub period: time::Duration,
+}
+
+/// Local devices announced on the network we announce remote servers on.
+#[derive(Clone)]
+pub struct ReverseConfig {
+    /// Where the devices are searched for.
+    pub search_interfaces: Interfaces,
+    /// Address of the first device's proxy, the next ones using the following ports.
+    pub proxy: SocketAddr,
+    pub search_target: String,
+    pub search_interval: time::Duration,
     pub period: time::Duration,
+    pub description_ttl: time::Duration,
+}
+
+/// SSDP traffic relayed between two interfaces, for networks joined by a router that doesn't forward multicast.
+#[derive(Clone)]
+pub struct RelayConfig {
+    /// The two interfaces, each one's traffic being relayed to the other.
+    pub interfaces: Interfaces,
+    /// Prefixes of the device types relayed, all of them if empty.
+    pub types: Vec<String>,
+    /// Address of the first device's proxy, the next ones using the following ports. LOCATIONs are relayed as is without it.
     pub proxy: Option<SocketAddr>,
-    pub broadcast_iface: Option<String>,
+}
+
+/// How the link with the other instance is established.
+#[derive(Clone)]
+pub enum PeerLink {
+    Listen(SocketAddr),
+    /// `host:port`, resolved on every attempt.
+    Connect(String),
+}
+
+/// Devices exchanged with another dlnaproxy instance over a TCP link, each side announcing the other's.
+#[derive(Clone)]
+pub struct PeerConfig {
+    pub link: PeerLink,
+    /// Both instances must know it to be linked.
+    pub secret: String,
+    /// Where our devices are searched for.
+    pub search_interfaces: Interfaces,
+    pub search_target: String,
+    pub search_interval: time::Duration,
+    /// Address of the proxy of the first device of the other side, the next ones using the following ports.
+    pub proxy: SocketAddr,
+    pub period: time::Duration,
+    pub description_ttl: time::Duration,
+}
+
+/// Disk cache of the images relayed by the HTTP proxies.
+pub struct ImageCacheConfig {
+    pub dir: PathBuf,
+    /// In bytes.
+    pub max_size: u64,
+}
+
+pub struct Config {
+    pub servers: Vec<ServerConfig>,
+    pub aggregate: Option<AggregateConfig>,
+    pub reverse: Option<ReverseConfig>,
+    pub relay: Option<RelayConfig>,
+    pub peer: Option<PeerConfig>,
+    pub image_cache: Option<ImageCacheConfig>,
+    pub interfaces: Interfaces,
+    /// Also announce on the IPv6 SSDP groups.
+    pub ipv6: bool,
     pub verbose: log::LevelFilter,
 }
 
@@ -35,6 +249,87 @@ impl TryFrom<CommandLineConf> for Config {
     }
 }
 
+impl RawServerConfig {
+    fn into_server_config(
+        self,
+        default_period: Option<u64>,
+        default_ttl: Option<u64>,
+        default_browse_cache: Option<u64>,
+    ) -> Result<ServerConfig> {
+        let description = match (self.description_url, self.discover) {
+            (Some(url), None) => RemoteDescription::Url(
+                Url::parse(&url).with_context(|| format!("Bad description URL '{}'.", url))?,
+            ),
+            (None, Some(hosts)) => RemoteDescription::Discover(hosts.parse()?),
+            _ => {
+                return Err(anyhow!(
+                    "A server has either a description URL or hosts to discover it on."
+                ))
+            }
+        };
+
+        let proxy: Option<SocketAddr> = self
+            .proxy
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Bad proxy address")?;
+
+        let serve: Option<SocketAddr> = self
+            .serve
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("Bad serve address")?;
+
+        Ok(ServerConfig {
+            description,
+            period: period_from(self.period.or(default_period)),
+            description_ttl: description_ttl_from(self.description_ttl.or(default_ttl)),
+            proxy,
+            proxy_mode: self.proxy_mode.unwrap_or_default(),
+            friendly_name: self.friendly_name,
+            model_name: self.model_name,
+            virtual_udn: self.virtual_udn.unwrap_or(false),
+            serve,
+            browse_cache_size: browse_cache_size_from(self.browse_cache.or(default_browse_cache)),
+            follow: self.follow,
+        })
+    }
+}
+
+fn period_from(period: Option<u64>) -> time::Duration {
+    time::Duration::from_secs(period.unwrap_or(DEFAULT_PERIOD))
+}
+
+fn description_ttl_from(ttl: Option<u64>) -> time::Duration {
+    time::Duration::from_secs(ttl.unwrap_or(DEFAULT_DESCRIPTION_TTL))
+}
+
+/// The cache size is configured in MiB.
+fn browse_cache_size_from(size: Option<u64>) -> usize {
+    (size.unwrap_or(DEFAULT_BROWSE_CACHE) * 1024 * 1024) as usize
+}
+
+/// The cache is enabled by its directory, its size is configured in MiB.
+fn image_cache_from(dir: Option<PathBuf>, size: Option<u64>) -> Option<ImageCacheConfig> {
+    dir.map(|dir| ImageCacheConfig {
+        dir,
+        max_size: size.unwrap_or(DEFAULT_IMAGE_CACHE_SIZE) * 1024 * 1024,
+    })
+}
+
+/// `all` stands for every multicast-capable interface but loopback.
+fn interfaces_from(names: Vec<String>) -> Interfaces {
+    if names.is_empty() {
+        Interfaces::Default
+    } else if names.iter().any(|name| name == "all") {
+        Interfaces::All
+    } else {
+        Interfaces::Named(names)
+    }
+}
+
 fn get_config(args: CommandLineConf) -> Result<Config> {
     println!("{:?}", args);
 
@@ -43,44 +338,176 @@ fn get_config(args: CommandLineConf) -> Result<Config> {
         .map(|file| fs::read_to_string(file).context("Could not open/read config file."))
         .transpose()?;
 
-    let (description_url, period, proxy, broadcast_iface, verbose) =
+    let (servers, aggregate, reverse, relay, peer, image_cache, ifaces, ipv6, verbose) =
         if let Some(config_file) = config_as_file {
             let raw_config: RawConfig =
                 toml::from_str(&config_file).context("failed to parse config file.")?;
 
-            let desc_url = raw_config
-                .description_url
-                .ok_or(anyhow!("Missing description URL"))
-                .and_then(|s| Url::parse(&s).context("Bad description URL."))?;
+            let default_period = raw_config.period;
+            let default_ttl = raw_config.description_ttl;
+            let default_browse_cache = raw_config.browse_cache;
+
+            //A top-level description URL (or discover) is the single-server shorthand, [[server]] tables add more.
+            let top_level = (raw_config.description_url.is_some() || raw_config.discover.is_some())
+                .then_some(RawServerConfig {
+                    description_url: raw_config.description_url,
+                    discover: raw_config.discover,
+                    period: raw_config.period,
+                    description_ttl: raw_config.description_ttl,
+                    proxy: raw_config.proxy,
+                    proxy_mode: raw_config.proxy_mode,
+                    friendly_name: raw_config.friendly_name,
+                    model_name: raw_config.model_name,
+                    virtual_udn: raw_config.virtual_udn,
+                    serve: raw_config.serve,
+                    browse_cache: raw_config.browse_cache,
+                    follow: raw_config.follow,
+                });
 
-            let period = raw_config.period;
+            let servers = top_level
+                .into_iter()
+                .chain(raw_config.server)
+                .map(|raw| {
+                    raw.into_server_config(default_period, default_ttl, default_browse_cache)
+                })
+                .collect::<Result<Vec<_>>>()?;
 
-            let proxy: Option<SocketAddr> = raw_config
-                .proxy
-                .as_deref()
-                .map(str::parse)
-                .transpose()
-                .context("Bad proxy address")?;
+            let aggregate = raw_config
+                .aggregate
+                .map(|raw| -> Result<AggregateConfig> {
+                    Ok(AggregateConfig {
+                        bind: raw.bind.parse().context("Bad aggregate bind address")?,
+                        friendly_name: raw.friendly_name.unwrap_or_else(|| "dlnaproxy".into()),
+                        period: period_from(raw.period.or(default_period)),
+                    })
+                })
+                .transpose()?;
+
+            let reverse = raw_config
+                .reverse
+                .map(|raw| -> Result<ReverseConfig> {
+                    Ok(ReverseConfig {
+                        search_interfaces: interfaces_from(
+                            raw.iface.map(Vec::from).unwrap_or_default(),
+                        ),
+                        proxy: raw.proxy.parse().context("Bad reverse proxy address")?,
+                        search_target: raw
+                            .search_target
+                            .unwrap_or_else(|| DEFAULT_SEARCH_TARGET.into()),
+                        search_interval: time::Duration::from_secs(
+                            raw.search_interval.unwrap_or(DEFAULT_SEARCH_INTERVAL),
+                        ),
+                        period: period_from(raw.period.or(default_period)),
+                        description_ttl: description_ttl_from(default_ttl),
+                    })
+                })
+                .transpose()?;
+
+            let relay = raw_config
+                .relay
+                .map(|raw| -> Result<RelayConfig> {
+                    if raw.iface.len() != 2 || raw.iface[0] == raw.iface[1] {
+                        return Err(anyhow!("The relay needs two distinct interfaces."));
+                    }
+
+                    Ok(RelayConfig {
+                        interfaces: Interfaces::Named(raw.iface),
+                        types: raw.types,
+                        proxy: raw
+                            .proxy
+                            .as_deref()
+                            .map(str::parse)
+                            .transpose()
+                            .context("Bad relay proxy address")?,
+                    })
+                })
+                .transpose()?;
+
+            let peer = raw_config
+                .peer
+                .map(|raw| -> Result<PeerConfig> {
+                    let link = match (raw.listen, raw.connect) {
+                        (Some(listen), None) => {
+                            PeerLink::Listen(listen.parse().context("Bad peer listen address")?)
+                        }
+                        (None, Some(connect)) => PeerLink::Connect(connect),
+                        _ => return Err(anyhow!("A peer either listens or connects.")),
+                    };
+
+                    let secret = raw
+                        .secret
+                        .filter(|secret| !secret.is_empty())
+                        .context("A peer needs a secret, shared with the other instance.")?;
+
+                    Ok(PeerConfig {
+                        link,
+                        secret,
+                        search_interfaces: interfaces_from(
+                            raw.iface.map(Vec::from).unwrap_or_default(),
+                        ),
+                        search_target: raw
+                            .search_target
+                            .unwrap_or_else(|| DEFAULT_PEER_SEARCH_TARGET.into()),
+                        search_interval: time::Duration::from_secs(
+                            raw.search_interval.unwrap_or(DEFAULT_SEARCH_INTERVAL),
+                        ),
+                        proxy: raw.proxy.parse().context("Bad peer proxy address")?,
+                        period: period_from(raw.period.or(default_period)),
+                        description_ttl: description_ttl_from(default_ttl),
+                    })
+                })
+                .transpose()?;
+
+            let image_cache = image_cache_from(raw_config.image_cache, raw_config.image_cache_size);
 
             (
-                desc_url,
-                period,
-                proxy,
-                raw_config.iface,
+                servers,
+                aggregate,
+                reverse,
+                relay,
+                peer,
+                image_cache,
+                raw_config.iface.map(Vec::from).unwrap_or_default(),
+                raw_config.ipv6.unwrap_or(false),
                 raw_config.verbose,
             )
         } else {
+            let description = match (args.description_url, args.discover) {
+                (Some(url), _) => RemoteDescription::Url(url),
+                (None, Some(hosts)) => RemoteDescription::Discover(hosts),
+                (None, None) => return Err(anyhow!("Missing description URL")),
+            };
+
+            let server = ServerConfig {
+                description,
+                period: period_from(args.interval),
+                description_ttl: description_ttl_from(args.description_ttl),
+                proxy: args.proxy,
+                proxy_mode: args.proxy_mode.unwrap_or_default(),
+                friendly_name: args.friendly_name,
+                model_name: args.model_name,
+                virtual_udn: args.virtual_udn,
+                serve: args.serve,
+                browse_cache_size: browse_cache_size_from(args.browse_cache),
+                follow: args.follow,
+            };
+
             (
-                args.description_url
-                    .ok_or(anyhow!("Missing description URL"))?,
-                args.interval,
-                args.proxy,
+                vec![server],
+                None,
+                None,
+                None,
+                None,
+                image_cache_from(args.image_cache, args.image_cache_size),
                 args.iface,
+                args.ipv6,
                 Some(args.verbose),
             )
         };
 
-    let period = period.or(Some(895)).map(time::Duration::from_secs).unwrap();
+    if servers.is_empty() && reverse.is_none() && relay.is_none() && peer.is_none() {
+        return Err(anyhow!("Missing description URL"));
+    }
 
     let verbose = verbose.map_or(log::LevelFilter::Warn, |v| match v {
         0 => log::LevelFilter::Warn,
@@ -90,10 +517,14 @@ fn get_config(args: CommandLineConf) -> Result<Config> {
     });
 
     Ok(Config {
-        description_url,
-        proxy,
-        period,
-        broadcast_iface,
+        servers,
+        aggregate,
+        reverse,
+        relay,
+        peer,
+        image_cache,
+        interfaces: interfaces_from(ifaces),
+        ipv6,
         verbose,
     })
 }
@@ -0,0 +1,159 @@
+use log::{info, warn};
+
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::Url;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::RemoteDescription;
+use crate::ssdp::search::{self, SearchHosts};
+
+/// What we look for on the hosts a server is discovered on.
+const DISCOVERY_TARGET: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+
+/// Delay between attempts while a server can't be found, or set up.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How often discovered servers are looked up again, in case their description moved.
+const REDISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps what `start` sets up for `servers` running until `shutdown` is triggered: discovers those that need it, then
+/// looks them up again every minute, stopping it and starting it again should one of them have moved.
+/// `start` is given the servers' description URLs, and a token stopping the tasks it returns.
+pub async fn keep_up<F, Fut>(servers: Vec<RemoteDescription>, start: F, shutdown: CancellationToken)
+where
+    F: Fn(Vec<Url>, CancellationToken) -> Fut,
+    Fut: Future<Output = Result<Vec<JoinHandle<()>>>>,
+{
+    loop {
+        let Some(urls) = description_urls(&servers, &shutdown).await else {
+            break;
+        };
+
+        let stop = shutdown.child_token();
+
+        match start(urls.clone(), stop.clone()).await {
+            Ok(tasks) => {
+                watch(&servers, &urls, &stop).await;
+
+                stop.cancel();
+
+                for task in tasks {
+                    let _ = task.await;
+                }
+            }
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Failed to set up {}: {:#}", names(&urls), e);
+
+                stop.cancel();
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => {}
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                }
+            }
+        }
+
+        if shutdown.is_cancelled() {
+            break;
+        }
+
+        info!(target: "dlnaproxy", "Setting up {} again.", names(&urls));
+    }
+}
+
+/// The description URL of each server, discovering those that need it.
+/// `None` if `shutdown` was triggered before they were all found.
+async fn description_urls(
+    servers: &[RemoteDescription],
+    shutdown: &CancellationToken,
+) -> Option<Vec<Url>> {
+    let mut urls = Vec::with_capacity(servers.len());
+
+    for server in servers {
+        let url = match server {
+            RemoteDescription::Url(url) => url.clone(),
+            RemoteDescription::Discover(hosts) => loop {
+                let found = tokio::select! {
+                    _ = shutdown.cancelled() => return None,
+                    found = discover(hosts) => found,
+                };
+
+                if let Some(url) = found {
+                    info!(target: "dlnaproxy", "Discovered {} on {}.", url, hosts);
+                    break url;
+                }
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => return None,
+                    _ = tokio::time::sleep(RETRY_DELAY) => {}
+                }
+            },
+        };
+
+        urls.push(url);
+    }
+
+    Some(urls)
+}
+
+/// Looks discovered servers up again until one of them moved from its URL in `urls`, or until `stop` is triggered,
+/// which happens as well should what was set up for them stop on its own.
+async fn watch(servers: &[RemoteDescription], urls: &[Url], stop: &CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => return,
+            _ = tokio::time::sleep(REDISCOVERY_INTERVAL) => {}
+        }
+
+        for (server, current) in servers.iter().zip(urls) {
+            let RemoteDescription::Discover(hosts) = server else {
+                continue;
+            };
+
+            //Not finding the server is no reason to give up on the URL we know, it may just be restarting.
+            let url = tokio::select! {
+                _ = stop.cancelled() => return,
+                found = discover(hosts) => match found {
+                    Some(url) => url,
+                    None => continue,
+                },
+            };
+
+            if url != *current {
+                info!(target: "dlnaproxy", "The server on {} moved from {} to {}.", hosts, current, url);
+                return;
+            }
+        }
+    }
+}
+
+fn names(urls: &[Url]) -> String {
+    urls.iter().map(Url::as_str).collect::<Vec<_>>().join(", ")
+}
+
+async fn discover(hosts: &SearchHosts) -> Option<Url> {
+    let mut found = match search::search_unicast(hosts, DISCOVERY_TARGET).await {
+        Ok(found) => found,
+        Err(e) => {
+            warn!(target: "dlnaproxy", "Failed to discover a server on {}: {:#}", hosts, e);
+            return None;
+        }
+    };
+
+    //The same one every time, rather than whichever answered first.
+    found.sort_by(|a, b| a.location.as_str().cmp(b.location.as_str()));
+
+    match found.as_slice() {
+        [] => warn!(target: "dlnaproxy", "No MediaServer answered on {}.", hosts),
+        [_] => {}
+        [first, ..] => {
+            warn!(target: "dlnaproxy", "Several MediaServers answered on {}, using {}.", hosts, first.location)
+        }
+    }
+
+    found.into_iter().next().map(|device| device.location)
+}
@@ -0,0 +1,212 @@
+use log::debug;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::description::element_text;
+use super::message::ResponseHead;
+use super::soap::{self, SoapAction};
+
+/// How long the remote's SystemUpdateID is trusted before cached responses are checked against it again.
+/// GENA events let us know of changes right away, when someone subscribed.
+const UPDATE_ID_TTL: Duration = Duration::from_secs(30);
+
+struct Entry {
+    response: ResponseHead,
+    body: Vec<u8>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Sum of the cached bodies' sizes.
+    size: usize,
+    /// Bumped on every access, to find the least recently used entry.
+    clock: u64,
+    system_update_id: Option<String>,
+    checked: Option<Instant>,
+}
+
+impl CacheState {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(entry) = lru.and_then(|key| self.entries.remove(&key)) {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+/// ContentDirectory Browse/Search responses, as sent by the remote server (before rewriting).
+/// Everything is dropped whenever the remote's SystemUpdateID changes.
+pub struct BrowseCache {
+    http_client: reqwest::Client,
+    max_size: usize,
+    state: Mutex<CacheState>,
+    /// Held while asking the remote server for its SystemUpdateID, a single request being enough.
+    check_lock: tokio::sync::Mutex<()>,
+}
+
+impl BrowseCache {
+    pub fn new(http_client: reqwest::Client, max_size: usize) -> Self {
+        BrowseCache {
+            http_client,
+            max_size,
+            state: Mutex::new(CacheState::default()),
+            check_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Key of a Browse/Search request, `None` if it can't be parsed.
+    /// Every argument is part of it, filter and sort criteria change the result too.
+    pub fn key(path: &str, action: &SoapAction, body: &[u8]) -> Option<String> {
+        let arguments = soap::parse_arguments(std::str::from_utf8(body).ok()?).ok()?;
+
+        let mut arguments: Vec<(String, String)> = arguments.into_iter().collect();
+        arguments.sort();
+
+        let mut key = format!("{}#{}", path, action.action);
+
+        for (name, value) in arguments {
+            key.push_str(&format!("\n{}={}", name, value));
+        }
+
+        Some(key)
+    }
+
+    /// A cached response, provided the remote's SystemUpdateID didn't change since.
+    /// `origin_url` is the ContentDirectory control URL on the remote server.
+    pub async fn get(
+        &self,
+        key: &str,
+        origin_url: &str,
+        action: &SoapAction<'_>,
+    ) -> Option<(ResponseHead, Vec<u8>)> {
+        if self.is_stale(key)? {
+            let _guard = self.check_lock.lock().await;
+
+            //Someone else checked while we were waiting for the lock.
+            if self.is_stale(key)? {
+                self.check_update_id(origin_url, action.service_type).await;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.clock += 1;
+        let clock = state.clock;
+
+        let entry = state.entries.get_mut(key)?;
+        entry.last_used = clock;
+
+        Some((entry.response.clone(), entry.body.clone()))
+    }
+
+    /// Whether the SystemUpdateID must be checked before serving `key`, `None` if it isn't cached.
+    fn is_stale(&self, key: &str) -> Option<bool> {
+        let state = self.state.lock().unwrap();
+
+        if !state.entries.contains_key(key) {
+            return None;
+        }
+
+        Some(
+            state
+                .checked
+                .is_none_or(|checked| checked.elapsed() > UPDATE_ID_TTL),
+        )
+    }
+
+    pub fn insert(&self, key: String, response: ResponseHead, body: Vec<u8>) {
+        if body.len() > self.max_size {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(previous) = state.entries.remove(&key) {
+            state.size -= previous.body.len();
+        }
+
+        while state.size + body.len() > self.max_size {
+            state.evict_lru();
+        }
+
+        state.clock += 1;
+        state.size += body.len();
+
+        let last_used = state.clock;
+        state.entries.insert(
+            key,
+            Entry {
+                response,
+                body,
+                last_used,
+            },
+        );
+    }
+
+    /// Takes note of the SystemUpdateID found in a ContentDirectory event, if any.
+    pub fn on_event(&self, body: &[u8]) {
+        let Some(id) = std::str::from_utf8(body)
+            .ok()
+            .and_then(|body| element_text(body, "SystemUpdateID"))
+        else {
+            return;
+        };
+
+        self.update_id(id);
+    }
+
+    async fn check_update_id(&self, origin_url: &str, service_type: &str) {
+        let output = soap::call(
+            &self.http_client,
+            origin_url,
+            service_type,
+            "GetSystemUpdateID",
+            &[],
+        )
+        .await;
+
+        match output {
+            Ok(output) => self.update_id(output.get("Id").map_or("", String::as_str)),
+            //Better safe than sorry.
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Couldn't get SystemUpdateID, dropping cached Browse responses: {:#}", e);
+
+                let mut state = self.state.lock().unwrap();
+                state.clear();
+                state.system_update_id = None;
+                state.checked = None;
+            }
+        }
+    }
+
+    fn update_id(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        //Responses cached before we first learn the ID are recent enough to be kept.
+        if state
+            .system_update_id
+            .as_deref()
+            .is_some_and(|known| known != id)
+        {
+            debug!(target: "dlnaproxy", "SystemUpdateID changed to {}, dropping cached Browse responses.", id);
+            state.clear();
+        }
+
+        state.system_update_id = Some(id.to_string());
+        state.checked = Some(Instant::now());
+    }
+}
@@ -0,0 +1,726 @@
+use log::{debug, warn};
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use reqwest::Url;
+
+use super::rewrite::UrlRewriter;
+use super::soap::{self, Fault};
+use crate::ssdp::cache::EndpointCache;
+use crate::ssdp::utils::EndpointInfo;
+
+/// Output arguments of an action, in the order the SCPD lists them.
+pub type ActionOutput = Vec<(&'static str, String)>;
+
+const DIDL_NAMESPACES: &[(&str, &str)] = &[
+    ("xmlns", "urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/"),
+    ("xmlns:dc", "http://purl.org/dc/elements/1.1/"),
+    ("xmlns:upnp", "urn:schemas-upnp-org:metadata-1-0/upnp/"),
+];
+
+/// Attributes of a DIDL-Lite root element, as key/value pairs.
+type DidlAttributes = Vec<(String, String)>;
+
+/// Separates the backend index from the backend's own object ID in the IDs we hand out.
+const ID_SEPARATOR: char = ':';
+
+/// A remote server whose ContentDirectory we expose.
+pub struct Backend {
+    description: Arc<EndpointCache>,
+    description_url: Url,
+    rewriter: UrlRewriter,
+    /// Where the proxy relaying the server's media listens, if any.
+    proxy: Option<SocketAddr>,
+}
+
+impl Backend {
+    pub fn new(
+        http_client: reqwest::Client,
+        description_url: &Url,
+        remote_addr: SocketAddr,
+        description_ttl: Duration,
+        proxy: Option<SocketAddr>,
+    ) -> Self {
+        Backend {
+            description: Arc::new(EndpointCache::new(
+                http_client,
+                description_url.as_str(),
+                description_ttl,
+            )),
+            description_url: description_url.clone(),
+            rewriter: UrlRewriter::new(description_url, remote_addr),
+            proxy,
+        }
+    }
+
+    pub fn description_url(&self) -> &Url {
+        &self.description_url
+    }
+
+    async fn info(&self) -> Result<Arc<EndpointInfo>> {
+        let info = self.description.get().await?;
+
+        if let Some(url_base) = &info.url_base {
+            self.rewriter.learn(url_base);
+        }
+
+        Ok(info)
+    }
+
+    /// Name of the server, falling back to its address when it can't be reached.
+    async fn name(&self) -> String {
+        match self.info().await {
+            Ok(info) => info.root_device().friendly_name.clone(),
+            Err(_) => self.description_url.host_str().unwrap_or_default().into(),
+        }
+    }
+
+    /// Invokes a ContentDirectory action on the remote server.
+    async fn call(
+        &self,
+        http_client: &reqwest::Client,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<HashMap<String, String>> {
+        self.call_service(http_client, "ContentDirectory", action, arguments)
+            .await
+    }
+
+    /// Invokes an action of the remote server's `service`, e.g. `ConnectionManager`.
+    async fn call_service(
+        &self,
+        http_client: &reqwest::Client,
+        service: &str,
+        action: &str,
+        arguments: &[(&str, &str)],
+    ) -> Result<HashMap<String, String>> {
+        let info = self.info().await?;
+
+        let (_, service_info) = info
+            .service(service)
+            .with_context(|| format!("Remote server has no {}.", service))?;
+
+        let base = match &info.url_base {
+            Some(url_base) => Url::parse(url_base).context("Bad URLBase.")?,
+            None => self.description_url.clone(),
+        };
+
+        let control_url = base
+            .join(&service_info.control_url)
+            .with_context(|| format!("Bad {} control URL.", service))?;
+
+        soap::call(
+            http_client,
+            control_url.as_str(),
+            &service_info.service_type,
+            action,
+            arguments,
+        )
+        .await
+    }
+
+    /// Points the media URLs of a DIDL-Lite document at the server's proxy, as reached through `host`.
+    fn rewrite_urls(&self, didl: &str, host: &str) -> String {
+        let Some(proxy) = self.proxy else {
+            return didl.to_string();
+        };
+
+        let proxy_authority = match proxy.ip().is_unspecified() {
+            true => format!("{}:{}", strip_port(host), proxy.port()),
+            false => proxy.to_string(),
+        };
+
+        self.rewriter.rewrite(didl, &proxy_authority).into_owned()
+    }
+}
+
+/// `host` from a `host[:port]` Host header.
+fn strip_port(host: &str) -> &str {
+    let port_start = match host.rfind(']') {
+        Some(bracket) => host[bracket..].rfind(':').map(|i| i + bracket),
+        None => host.rfind(':'),
+    };
+
+    match port_start {
+        Some(i) => &host[..i],
+        None => host,
+    }
+}
+
+/// What the ContentDirectory of our own MediaServer is made of.
+pub enum ContentDirectory {
+    /// Several remote servers, under a root of ours.
+    Aggregate(Aggregator),
+    /// A single remote server, as is.
+    Forward(Forwarder),
+}
+
+impl ContentDirectory {
+    /// Name our MediaServer goes by.
+    pub async fn name(&self) -> String {
+        match self {
+            ContentDirectory::Aggregate(aggregator) => aggregator.name.clone(),
+            ContentDirectory::Forward(forwarder) => forwarder.backend.name().await,
+        }
+    }
+
+    /// Runs a ContentDirectory action; `host` is the Host header the client reached us with.
+    pub async fn handle(
+        &self,
+        action: &str,
+        arguments: &HashMap<String, String>,
+        host: &str,
+    ) -> Result<ActionOutput, Fault> {
+        match self {
+            ContentDirectory::Aggregate(aggregator) => {
+                aggregator.handle(action, arguments, host).await
+            }
+            ContentDirectory::Forward(forwarder) => forwarder.handle(action, arguments, host).await,
+        }
+    }
+}
+
+/// Relays the ContentDirectory and ConnectionManager actions of a single remote server.
+/// Only the media URLs of Browse/Search results are rewritten.
+pub struct Forwarder {
+    http_client: reqwest::Client,
+    backend: Backend,
+}
+
+impl Forwarder {
+    pub fn new(http_client: reqwest::Client, backend: Backend) -> Self {
+        Forwarder {
+            http_client,
+            backend,
+        }
+    }
+
+    async fn handle(
+        &self,
+        action: &str,
+        arguments: &HashMap<String, String>,
+        host: &str,
+    ) -> Result<ActionOutput, Fault> {
+        let mut output = self.relay("ContentDirectory", action, arguments).await?;
+
+        for (name, value) in output.iter_mut() {
+            if *name == "Result" {
+                *value = self.backend.rewrite_urls(value, host);
+            }
+        }
+
+        Ok(output)
+    }
+
+    pub async fn connection_manager(
+        &self,
+        action: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<ActionOutput, Fault> {
+        self.relay("ConnectionManager", action, arguments).await
+    }
+
+    /// Forwards the arguments our SCPD declares, and returns the outputs it declares in its order.
+    async fn relay(
+        &self,
+        service: &str,
+        action: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<ActionOutput, Fault> {
+        let outputs = action_outputs(action);
+
+        if outputs.is_empty() {
+            return Err(Fault::new(401, "Invalid Action"));
+        }
+
+        let forwarded: Vec<(&str, &str)> = action_arguments(action)
+            .iter()
+            .map(|&name| (name, arguments.get(name).map_or("", String::as_str)))
+            .collect();
+
+        let mut output = self
+            .backend
+            .call_service(&self.http_client, service, action, &forwarded)
+            .await
+            .map_err(backend_fault)?;
+
+        Ok(outputs
+            .iter()
+            .map(|&name| (name, output.remove(name).unwrap_or_default()))
+            .collect())
+    }
+}
+
+/// A ContentDirectory whose root lists every remote server as a container.
+/// Object IDs are those of the remote servers, prefixed with the server's index.
+pub struct Aggregator {
+    http_client: reqwest::Client,
+    name: String,
+    backends: Vec<Backend>,
+}
+
+impl Aggregator {
+    pub fn new(http_client: reqwest::Client, name: &str, backends: Vec<Backend>) -> Self {
+        Aggregator {
+            http_client,
+            name: name.into(),
+            backends,
+        }
+    }
+
+    async fn handle(
+        &self,
+        action: &str,
+        arguments: &HashMap<String, String>,
+        host: &str,
+    ) -> Result<ActionOutput, Fault> {
+        let arg = |name: &str| arguments.get(name).map(String::as_str).unwrap_or_default();
+
+        match action {
+            "Browse" => {
+                let start = parse_count(arg("StartingIndex"))?;
+                let count = parse_count(arg("RequestedCount"))?;
+
+                match (arg("ObjectID"), arg("BrowseFlag")) {
+                    ("0", "BrowseMetadata") => {
+                        let root = container("0", "-1", &self.name, Some(self.backends.len()));
+
+                        Ok(browse_output(didl(&[], &[root]), 1, 1, "0"))
+                    }
+                    ("0", "BrowseDirectChildren") => Ok(self.browse_root(start, count).await),
+                    ("0", _) => Err(Fault::invalid_args()),
+                    (object_id, _) => {
+                        let (backend, object_id) = self.backend_for(object_id)?;
+
+                        self.forward(backend, "Browse", "ObjectID", object_id, arguments, host)
+                            .await
+                    }
+                }
+            }
+            "Search" => match arg("ContainerID") {
+                "0" => self.search_all(arguments, host).await,
+                container_id => {
+                    let (backend, container_id) = self.backend_for(container_id)?;
+
+                    self.forward(
+                        backend,
+                        "Search",
+                        "ContainerID",
+                        container_id,
+                        arguments,
+                        host,
+                    )
+                    .await
+                }
+            },
+            "GetSearchCapabilities" => Ok(vec![(
+                "SearchCaps",
+                self.first_capabilities(action, "SearchCaps").await,
+            )]),
+            "GetSortCapabilities" => Ok(vec![(
+                "SortCaps",
+                self.first_capabilities(action, "SortCaps").await,
+            )]),
+            "GetSystemUpdateID" => Ok(vec![("Id", self.system_update_id().await.to_string())]),
+            _ => Err(Fault::new(401, "Invalid Action")),
+        }
+    }
+
+    fn backend_for<'a>(&self, object_id: &'a str) -> Result<(usize, &'a str), Fault> {
+        object_id
+            .split_once(ID_SEPARATOR)
+            .and_then(|(index, object_id)| Some((index.parse().ok()?, object_id)))
+            .filter(|(index, _)| *index < self.backends.len())
+            .ok_or_else(|| Fault::new(701, "No such object"))
+    }
+
+    async fn browse_root(&self, start: usize, count: usize) -> ActionOutput {
+        let mut containers = Vec::new();
+
+        for (index, backend) in self.backends.iter().enumerate().skip(start) {
+            if count != 0 && containers.len() == count {
+                break;
+            }
+
+            let id = virtual_id(index, "0");
+            containers.push(container(&id, "0", &backend.name().await, None));
+        }
+
+        let returned = containers.len();
+
+        browse_output(didl(&[], &containers), returned, self.backends.len(), "0")
+    }
+
+    /// Relays an action on one of the backend's objects, translating IDs both ways.
+    async fn forward(
+        &self,
+        index: usize,
+        action: &str,
+        id_argument: &'static str,
+        object_id: &str,
+        arguments: &HashMap<String, String>,
+        host: &str,
+    ) -> Result<ActionOutput, Fault> {
+        let forwarded: Vec<(&str, &str)> = action_arguments(action)
+            .iter()
+            .map(|&name| match name == id_argument {
+                true => (name, object_id),
+                false => (name, arguments.get(name).map_or("", String::as_str)),
+            })
+            .collect();
+
+        let backend = &self.backends[index];
+
+        let output = backend
+            .call(&self.http_client, action, &forwarded)
+            .await
+            .map_err(backend_fault)?;
+
+        let result = output.get("Result").map_or("", String::as_str);
+        let result = rewrite_ids(result, index).map_err(|e| {
+            warn!(target: "dlnaproxy", "Couldn't rewrite {} result: {:#}", action, e);
+            Fault::action_failed()
+        })?;
+
+        let get = |name: &str| output.get(name).cloned().unwrap_or_else(|| "0".into());
+
+        Ok(vec![
+            ("Result", backend.rewrite_urls(&result, host)),
+            ("NumberReturned", get("NumberReturned")),
+            ("TotalMatches", get("TotalMatches")),
+            ("UpdateID", get("UpdateID")),
+        ])
+    }
+
+    /// Searches every backend from its root, merging the results.
+    async fn search_all(
+        &self,
+        arguments: &HashMap<String, String>,
+        host: &str,
+    ) -> Result<ActionOutput, Fault> {
+        let arg = |name: &str| arguments.get(name).map(String::as_str).unwrap_or_default();
+
+        let start = parse_count(arg("StartingIndex"))?;
+        let count = parse_count(arg("RequestedCount"))?;
+
+        //Every backend is asked for everything up to the end of the requested window.
+        let wanted = match count {
+            0 => "0".to_string(),
+            count => (start + count).to_string(),
+        };
+
+        let mut root_attributes: Vec<(String, String)> = Vec::new();
+        let mut objects = Vec::new();
+        let mut total = 0;
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            let forwarded = [
+                ("ContainerID", "0"),
+                ("SearchCriteria", arg("SearchCriteria")),
+                ("Filter", arg("Filter")),
+                ("StartingIndex", "0"),
+                ("RequestedCount", wanted.as_str()),
+                ("SortCriteria", arg("SortCriteria")),
+            ];
+
+            let output = match backend.call(&self.http_client, "Search", &forwarded).await {
+                Ok(output) => output,
+                Err(e) => {
+                    debug!(target: "dlnaproxy", "Search failed on {}: {:#}", backend.description_url, e);
+                    continue;
+                }
+            };
+
+            let result = output.get("Result").map_or("", String::as_str);
+
+            let split = rewrite_ids(result, index)
+                .and_then(|didl| split_didl(&backend.rewrite_urls(&didl, host)));
+
+            let (attributes, backend_objects) = match split {
+                Ok(split) => split,
+                Err(e) => {
+                    warn!(target: "dlnaproxy", "Couldn't merge Search result: {:#}", e);
+                    continue;
+                }
+            };
+
+            for attribute in attributes {
+                if !root_attributes.iter().any(|(key, _)| *key == attribute.0) {
+                    root_attributes.push(attribute);
+                }
+            }
+
+            total += output
+                .get("TotalMatches")
+                .and_then(|total| total.parse::<usize>().ok())
+                .unwrap_or(backend_objects.len());
+
+            objects.extend(backend_objects);
+        }
+
+        let window: Vec<String> = objects
+            .into_iter()
+            .skip(start)
+            .take(match count {
+                0 => usize::MAX,
+                count => count,
+            })
+            .collect();
+
+        Ok(browse_output(
+            didl(&root_attributes, &window),
+            window.len(),
+            total,
+            "0",
+        ))
+    }
+
+    async fn first_capabilities(&self, action: &str, argument: &str) -> String {
+        for backend in &self.backends {
+            if let Ok(mut output) = backend.call(&self.http_client, action, &[]).await {
+                return output.remove(argument).unwrap_or_default();
+            }
+        }
+
+        String::new()
+    }
+
+    /// Changes whenever one of the backends' does.
+    async fn system_update_id(&self) -> u32 {
+        let mut id: u32 = 0;
+
+        for backend in &self.backends {
+            if let Ok(output) = backend
+                .call(&self.http_client, "GetSystemUpdateID", &[])
+                .await
+            {
+                let backend_id = output.get("Id").and_then(|id| id.parse().ok());
+                id = id.wrapping_add(backend_id.unwrap_or(0));
+            }
+        }
+
+        id
+    }
+}
+
+/// Output arguments of the actions we relay, as our SCPDs list them.
+fn action_outputs(action: &str) -> &'static [&'static str] {
+    match action {
+        "Browse" | "Search" => &["Result", "NumberReturned", "TotalMatches", "UpdateID"],
+        "GetSearchCapabilities" => &["SearchCaps"],
+        "GetSortCapabilities" => &["SortCaps"],
+        "GetSystemUpdateID" => &["Id"],
+        "GetProtocolInfo" => &["Source", "Sink"],
+        "GetCurrentConnectionIDs" => &["ConnectionIDs"],
+        "GetCurrentConnectionInfo" => &[
+            "RcsID",
+            "AVTransportID",
+            "ProtocolInfo",
+            "PeerConnectionManager",
+            "PeerConnectionID",
+            "Direction",
+            "Status",
+        ],
+        _ => &[],
+    }
+}
+
+/// Input arguments of the actions we relay, as our SCPDs list them.
+fn action_arguments(action: &str) -> &'static [&'static str] {
+    match action {
+        "Browse" => &[
+            "ObjectID",
+            "BrowseFlag",
+            "Filter",
+            "StartingIndex",
+            "RequestedCount",
+            "SortCriteria",
+        ],
+        "Search" => &[
+            "ContainerID",
+            "SearchCriteria",
+            "Filter",
+            "StartingIndex",
+            "RequestedCount",
+            "SortCriteria",
+        ],
+        "GetCurrentConnectionInfo" => &["ConnectionID"],
+        _ => &[],
+    }
+}
+
+/// Keeps the remote server's own UPnP errors, e.g. 701 for an object that went away.
+fn backend_fault(e: anyhow::Error) -> Fault {
+    match e.downcast::<Fault>() {
+        Ok(fault) => fault,
+        Err(e) => {
+            warn!(target: "dlnaproxy", "Remote SOAP call failed: {:#}", e);
+            Fault::action_failed()
+        }
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, Fault> {
+    match value.trim() {
+        "" => Ok(0),
+        value => value.parse().map_err(|_| Fault::invalid_args()),
+    }
+}
+
+fn browse_output(result: String, returned: usize, total: usize, update_id: &str) -> ActionOutput {
+    vec![
+        ("Result", result),
+        ("NumberReturned", returned.to_string()),
+        ("TotalMatches", total.to_string()),
+        ("UpdateID", update_id.into()),
+    ]
+}
+
+fn virtual_id(index: usize, object_id: &str) -> String {
+    match object_id {
+        //The remote server's root hangs from ours.
+        "-1" => "0".into(),
+        object_id => format!("{}{}{}", index, ID_SEPARATOR, object_id),
+    }
+}
+
+fn container(id: &str, parent_id: &str, title: &str, child_count: Option<usize>) -> String {
+    let child_count = child_count
+        .map(|count| format!(" childCount=\"{}\"", count))
+        .unwrap_or_default();
+
+    format!(
+        "<container id=\"{}\" parentID=\"{}\" restricted=\"1\" searchable=\"1\"{}>\
+<dc:title>{}</dc:title>\
+<upnp:class>object.container.storageFolder</upnp:class>\
+</container>",
+        escape(id),
+        escape(parent_id),
+        child_count,
+        escape(title)
+    )
+}
+
+/// A DIDL-Lite document made of `objects`, declaring the usual namespaces on top of `extra_attributes`.
+fn didl(extra_attributes: &[(String, String)], objects: &[String]) -> String {
+    let mut didl = String::from("<DIDL-Lite");
+
+    for (key, value) in DIDL_NAMESPACES {
+        if !extra_attributes.iter().any(|(extra, _)| extra == key) {
+            didl.push_str(&format!(" {}=\"{}\"", key, value));
+        }
+    }
+
+    for (key, value) in extra_attributes {
+        didl.push_str(&format!(" {}=\"{}\"", key, escape(value)));
+    }
+
+    didl.push('>');
+    didl.extend(objects.iter().map(String::as_str));
+    didl.push_str("</DIDL-Lite>");
+    didl
+}
+
+/// Prefixes the IDs of every object in a remote server's DIDL-Lite document with its index.
+fn rewrite_ids(didl: &str, index: usize) -> Result<String> {
+    let mut reader = Reader::from_str(didl);
+    let mut writer = Writer::new(Cursor::new(Vec::with_capacity(didl.len())));
+
+    loop {
+        let event = reader.read_event().context("Failed to parse DIDL-Lite.")?;
+
+        match event {
+            Event::Eof => break,
+            Event::Start(ref start) if is_object(start) => {
+                writer.write_event(Event::Start(with_virtual_ids(start, index)?))?
+            }
+            Event::Empty(ref empty) if is_object(empty) => {
+                writer.write_event(Event::Empty(with_virtual_ids(empty, index)?))?
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+
+    String::from_utf8(writer.into_inner().into_inner()).context("Rewritten DIDL-Lite isn't UTF-8.")
+}
+
+fn is_object(element: &BytesStart) -> bool {
+    matches!(element.local_name().as_ref(), b"container" | b"item")
+}
+
+fn with_virtual_ids(element: &BytesStart, index: usize) -> Result<BytesStart<'static>> {
+    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+    let mut rewritten = BytesStart::new(name);
+
+    for attribute in element.attributes() {
+        let attribute = attribute.context("Bad DIDL-Lite attribute.")?;
+
+        match attribute.key.as_ref() {
+            key @ (b"id" | b"parentID" | b"refID") => {
+                let value = attribute.unescape_value()?;
+                let key = String::from_utf8_lossy(key);
+
+                rewritten.push_attribute((key.as_ref(), virtual_id(index, &value).as_str()));
+            }
+            _ => rewritten.push_attribute(attribute),
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// Root attributes (namespace declarations) and top-level objects of a DIDL-Lite document.
+fn split_didl(didl: &str) -> Result<(DidlAttributes, Vec<String>)> {
+    let mut reader = Reader::from_str(didl);
+
+    let mut attributes = Vec::new();
+    let mut objects = Vec::new();
+
+    let mut depth = 0;
+    let mut object_start = 0;
+
+    loop {
+        let before = reader.buffer_position() as usize;
+
+        match reader.read_event().context("Failed to parse DIDL-Lite.")? {
+            Event::Eof => break,
+            Event::Start(start) => {
+                if depth == 0 {
+                    for attribute in start.attributes() {
+                        let attribute = attribute.context("Bad DIDL-Lite attribute.")?;
+
+                        attributes.push((
+                            String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+                            attribute.unescape_value()?.into_owned(),
+                        ));
+                    }
+                } else if depth == 1 {
+                    object_start = before;
+                }
+
+                depth += 1;
+            }
+            Event::Empty(_) if depth == 1 => {
+                objects.push(didl[before..reader.buffer_position() as usize].to_string());
+            }
+            Event::End(_) => {
+                depth -= 1;
+
+                if depth == 1 {
+                    objects.push(didl[object_start..reader.buffer_position() as usize].to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok((attributes, objects))
+}
@@ -0,0 +1,129 @@
+use quick_xml::escape::escape;
+use uuid::Uuid;
+
+/// Namespace of the virtual UDNs derived from the remote devices' own.
+const VIRTUAL_UDN_NAMESPACE: Uuid = Uuid::from_u128(0x6a1f_3c2e_94d7_4b0a_8e55_d2c1_7f90_b346);
+
+/// Changes made to the description served through the proxy.
+#[derive(Clone, Debug, Default)]
+pub struct DescriptionOverrides {
+    /// Names announced in place of the remote server's own, as templates such as `{friendlyName} (remote)`.
+    pub friendly_name: Option<String>,
+    pub model_name: Option<String>,
+    /// Replace every UDN with one derived from it, so that the proxied device isn't mistaken for the remote one.
+    pub virtual_udn: bool,
+}
+
+impl DescriptionOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.friendly_name.is_none() && self.model_name.is_none() && !self.virtual_udn
+    }
+
+    /// Applies the overrides to the root device, the first one in the description.
+    pub fn apply(&self, description: &str) -> String {
+        let vars = [
+            ("friendlyName", element_text(description, "friendlyName")),
+            ("modelName", element_text(description, "modelName")),
+        ];
+
+        let mut description = description.to_string();
+
+        for (name, template) in [
+            ("friendlyName", &self.friendly_name),
+            ("modelName", &self.model_name),
+        ] {
+            if let Some(template) = template {
+                description = replace_element_text(&description, name, &render(template, &vars));
+            }
+        }
+
+        //SSDP announcements are built from this very description, they get the same UDNs.
+        if self.virtual_udn {
+            description = virtualize_udns(&description);
+        }
+
+        description
+    }
+}
+
+/// Stable `uuid:` standing for `udn`.
+pub fn virtual_udn(udn: &str) -> String {
+    format!(
+        "uuid:{}",
+        Uuid::new_v5(&VIRTUAL_UDN_NAMESPACE, udn.as_bytes())
+    )
+}
+
+/// Replaces the UDN of the root device and of every embedded one.
+fn virtualize_udns(description: &str) -> String {
+    const OPEN: &str = "<UDN>";
+    const CLOSE: &str = "</UDN>";
+
+    let mut rewritten = String::with_capacity(description.len());
+    let mut rest = description;
+
+    while let Some(start) = rest.find(OPEN) {
+        let start = start + OPEN.len();
+
+        let Some(len) = rest[start..].find(CLOSE) else {
+            break;
+        };
+
+        rewritten.push_str(&rest[..start]);
+        rewritten.push_str(&virtual_udn(rest[start..start + len].trim()));
+
+        rest = &rest[start + len..];
+    }
+
+    rewritten.push_str(rest);
+    rewritten
+}
+
+/// Substitutes `{name}` placeholders with the (already escaped) values found in the description, escaping the rest.
+fn render(template: &str, vars: &[(&str, Option<&str>)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+
+        let name = &rest[start + 1..start + len];
+
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) => {
+                rendered.push_str(&escape(&rest[..start]));
+                rendered.push_str(value.unwrap_or_default());
+            }
+            None => rendered.push_str(&escape(&rest[..start + len + 1])),
+        }
+
+        rest = &rest[start + len + 1..];
+    }
+
+    rendered.push_str(&escape(rest));
+    rendered
+}
+
+/// Text content of the first `<name>` element, good enough for the flat elements of a description.
+pub fn element_text<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let (start, end) = element_bounds(xml, name)?;
+
+    Some(xml[start..end].trim())
+}
+
+fn replace_element_text(xml: &str, name: &str, text: &str) -> String {
+    match element_bounds(xml, name) {
+        Some((start, end)) => format!("{}{}{}", &xml[..start], text, &xml[end..]),
+        None => xml.to_string(),
+    }
+}
+
+/// Bounds of the text content of the first `<name>` element.
+fn element_bounds(xml: &str, name: &str) -> Option<(usize, usize)> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+
+    Some((start, end))
+}
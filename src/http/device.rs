@@ -0,0 +1,72 @@
+use quick_xml::escape::escape;
+
+pub const DESCRIPTION_PATH: &str = "/description.xml";
+
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONTENT_DIRECTORY_SCPD_PATH: &str = "/ContentDirectory.xml";
+pub const CONTENT_DIRECTORY_CONTROL_PATH: &str = "/ctl/ContentDirectory";
+pub const CONTENT_DIRECTORY_EVENT_PATH: &str = "/evt/ContentDirectory";
+
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+pub const CONNECTION_MANAGER_SCPD_PATH: &str = "/ConnectionManager.xml";
+pub const CONNECTION_MANAGER_CONTROL_PATH: &str = "/ctl/ConnectionManager";
+pub const CONNECTION_MANAGER_EVENT_PATH: &str = "/evt/ConnectionManager";
+
+pub const CONTENT_DIRECTORY_SCPD: &str = include_str!("scpd/ContentDirectory.xml");
+pub const CONNECTION_MANAGER_SCPD: &str = include_str!("scpd/ConnectionManager.xml");
+
+/// `SERVER` header of our responses, which SSDP announcements reuse.
+pub fn server_header() -> String {
+    format!(
+        "{}/1.0 UPnP/1.0 dlnaproxy/{}",
+        std::env::consts::OS,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Root description of the MediaServer dlnaproxy serves itself. URLs are relative to the description's.
+pub fn root_description(friendly_name: &str, udn: &str) -> String {
+    format!(
+        "\
+<?xml version=\"1.0\" encoding=\"utf-8\"?>\r\n\
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">\
+<specVersion><major>1</major><minor>0</minor></specVersion>\
+<device>\
+<deviceType>urn:schemas-upnp-org:device:MediaServer:1</deviceType>\
+<friendlyName>{friendly_name}</friendlyName>\
+<manufacturer>dlnaproxy</manufacturer>\
+<modelName>dlnaproxy</modelName>\
+<modelNumber>{version}</modelNumber>\
+<UDN>{udn}</UDN>\
+<dlna:X_DLNADOC xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">DMS-1.50</dlna:X_DLNADOC>\
+<serviceList>\
+<service>\
+<serviceType>{cds}</serviceType>\
+<serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>\
+<SCPDURL>{cds_scpd}</SCPDURL>\
+<controlURL>{cds_control}</controlURL>\
+<eventSubURL>{cds_event}</eventSubURL>\
+</service>\
+<service>\
+<serviceType>{cms}</serviceType>\
+<serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>\
+<SCPDURL>{cms_scpd}</SCPDURL>\
+<controlURL>{cms_control}</controlURL>\
+<eventSubURL>{cms_event}</eventSubURL>\
+</service>\
+</serviceList>\
+</device>\
+</root>",
+        friendly_name = escape(friendly_name),
+        version = env!("CARGO_PKG_VERSION"),
+        udn = escape(udn),
+        cds = CONTENT_DIRECTORY,
+        cds_scpd = CONTENT_DIRECTORY_SCPD_PATH,
+        cds_control = CONTENT_DIRECTORY_CONTROL_PATH,
+        cds_event = CONTENT_DIRECTORY_EVENT_PATH,
+        cms = CONNECTION_MANAGER,
+        cms_scpd = CONNECTION_MANAGER_SCPD_PATH,
+        cms_control = CONNECTION_MANAGER_CONTROL_PATH,
+        cms_event = CONNECTION_MANAGER_EVENT_PATH,
+    )
+}
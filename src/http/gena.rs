@@ -0,0 +1,247 @@
+use log::{debug, info, trace, warn};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use tokio::io::{AsyncWriteExt as _, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::http::message::{read_head, Headers, RequestHead, ResponseHead};
+
+/// Path under which the remote server reaches us with event notifications.
+pub const EVENT_PATH_PREFIX: &str = "/_dlnaproxy/event/";
+
+/// How long we wait on a LAN subscriber to acknowledge an event.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a SUBSCRIBE may wait on the remote server's answer before its entry is reclaimed.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// UDA's recommended duration, for answers without a usable TIMEOUT.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1800);
+
+/// Longest we keep a subscription without a renewal, infinite ones included.
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+
+/// Headers of a NOTIFY that are relayed to the LAN subscriber.
+const EVENT_HEADERS: &[&str] = &["Content-Type", "NT", "NTS", "SID", "SEQ"];
+
+struct Subscription {
+    /// LAN callbacks, tried in order as per UDA.
+    callbacks: Vec<String>,
+    sid: Option<String>,
+    expires: Instant,
+}
+
+/// GENA subscriptions made by LAN control points through the proxy.
+#[derive(Default)]
+pub struct Subscriptions {
+    entries: Mutex<HashMap<String, Subscription>>,
+}
+
+/// `Second-1800` or `Second-infinite`, as found in TIMEOUT headers, capped at `MAX_TIMEOUT`.
+fn parse_timeout(timeout: Option<&str>) -> Instant {
+    let timeout = match timeout.map(|timeout| timeout.trim().strip_prefix("Second-")) {
+        Some(Some("infinite")) => MAX_TIMEOUT,
+        Some(Some(seconds)) => seconds
+            .parse()
+            .map_or(DEFAULT_TIMEOUT, Duration::from_secs)
+            .min(MAX_TIMEOUT),
+        _ => DEFAULT_TIMEOUT,
+    };
+
+    Instant::now() + timeout
+}
+
+/// CALLBACK value pointing at us, `addr` being where the remote server can reach the proxy.
+pub fn callback_header(addr: SocketAddr, token: &str) -> String {
+    format!("<http://{}{}{}>", addr, EVENT_PATH_PREFIX, token)
+}
+
+impl Subscriptions {
+    /// Registers the LAN callbacks of a new SUBSCRIBE, returning the token standing for them.
+    pub fn register(&self, callback_header: &str) -> Option<String> {
+        let callbacks: Vec<String> = callback_header
+            .split('<')
+            .filter_map(|part| part.split_once('>'))
+            .map(|(url, _)| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+
+        if callbacks.is_empty() {
+            return None;
+        }
+
+        let token = format!("{:016x}", rand::random::<u64>());
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let now = Instant::now();
+        entries.retain(|_, sub| sub.expires > now);
+
+        //Until the remote server answers, should it ever.
+        entries.insert(
+            token.clone(),
+            Subscription {
+                callbacks,
+                sid: None,
+                expires: now + PENDING_TIMEOUT,
+            },
+        );
+
+        Some(token)
+    }
+
+    /// Updates the subscriptions after the remote server answered a SUBSCRIBE, whether initial
+    /// (`token` is set) or a renewal (`request_sid` is set).
+    pub fn on_subscribe_response(
+        &self,
+        token: Option<&str>,
+        request_sid: Option<&str>,
+        response: &ResponseHead,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+
+        let expires = parse_timeout(response.headers.get_str("TIMEOUT"));
+
+        match (token, request_sid) {
+            (Some(token), _) if response.status != 200 => {
+                entries.remove(token);
+            }
+            (Some(token), _) => {
+                if let Some(sub) = entries.get_mut(token) {
+                    sub.sid = response.headers.get_str("SID").map(String::from);
+                    sub.expires = expires;
+
+                    info!(target: "dlnaproxy", "Relaying events of {} to {}.", sub.sid.as_deref().unwrap_or("?"), sub.callbacks.join(", "));
+                }
+            }
+            (None, Some(sid)) if response.status == 200 => {
+                if let Some(sub) = entries
+                    .values_mut()
+                    .find(|sub| sub.sid.as_deref() == Some(sid))
+                {
+                    sub.expires = expires;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops the subscription behind `token`, which the remote server never answered.
+    pub fn forget(&self, token: &str) {
+        self.entries.lock().unwrap().remove(token);
+    }
+
+    pub fn unsubscribe(&self, sid: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, sub| sub.sid.as_deref() != Some(sid));
+
+        debug!(target: "dlnaproxy", "Stopped relaying events of {}.", sid);
+    }
+
+    /// LAN callbacks of the subscription behind `token`, provided the event carries its SID.
+    /// Events may beat the SUBSCRIBE response to us, the SID isn't known yet then.
+    fn callbacks(&self, token: &str, event: &RequestHead) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        let sub = entries.get(token)?;
+
+        match (sub.sid.as_deref(), event.headers.get_str("SID")) {
+            (Some(sid), Some(event_sid)) if sid == event_sid => Some(sub.callbacks.clone()),
+            (None, Some(_)) => Some(sub.callbacks.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether `event` belongs to the subscription behind `token`.
+    pub fn knows(&self, token: &str, event: &RequestHead) -> bool {
+        self.callbacks(token, event).is_some()
+    }
+
+    /// Delivers an event sent by the remote server to the LAN subscriber behind `token`.
+    pub async fn relay(
+        &self,
+        token: &str,
+        request: &RequestHead,
+        body: &[u8],
+    ) -> (u16, &'static str) {
+        let Some(callbacks) = self.callbacks(token, request) else {
+            debug!(target: "dlnaproxy", "Event for unknown subscription {}.", token);
+            return (412, "Precondition Failed");
+        };
+
+        for callback in &callbacks {
+            match time::timeout(DELIVERY_TIMEOUT, deliver(callback, request, body)).await {
+                Ok(Ok(status)) => {
+                    trace!(target: "dlnaproxy", "Event delivered to {} ({}).", callback, status);
+                    return (200, "OK");
+                }
+                Ok(Err(e)) => {
+                    warn!(target: "dlnaproxy", "Failed to deliver event to {}: {}", callback, e);
+                }
+                Err(_) => {
+                    warn!(target: "dlnaproxy", "Timed out delivering event to {}.", callback);
+                }
+            }
+        }
+
+        (502, "Bad Gateway")
+    }
+}
+
+async fn deliver(callback: &str, event: &RequestHead, body: &[u8]) -> io::Result<u16> {
+    let url = Url::parse(callback).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let addr = url
+        .socket_addrs(|| Some(80))?
+        .into_iter()
+        .next()
+        .ok_or(io::ErrorKind::AddrNotAvailable)?;
+
+    let mut headers = Headers::default();
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+
+    headers.set("Host", host);
+
+    for name in EVENT_HEADERS {
+        if let Some(value) = event.headers.get(name) {
+            headers.set(name, value);
+        }
+    }
+
+    headers.set("Content-Length", body.len().to_string());
+    headers.set("Connection", "close");
+
+    let notify = RequestHead {
+        method: "NOTIFY".into(),
+        target: match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        version: 1,
+        headers,
+    };
+
+    let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+
+    writer.write_all(&notify.to_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+
+    let head = read_head(&mut BufReader::new(reader))
+        .await?
+        .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+    Ok(ResponseHead::parse(&head)?.status)
+}
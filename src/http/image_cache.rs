@@ -0,0 +1,430 @@
+use log::{debug, info, warn};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+use super::message::{RequestHead, ResponseHead};
+
+/// Namespace of the cached files' names, derived from the URLs they were fetched from.
+const FILE_NAMESPACE: Uuid = Uuid::from_u128(0x0c3d_6e1a_5b27_4f88_a9d3_41e7_26b5_c08f);
+
+/// Only thumbnails and album art are worth keeping, not full-size pictures.
+pub const MAX_IMAGE_SIZE: u64 = 1024 * 1024;
+
+/// How long an image is served without asking the remote server when it doesn't say (`max-age`), as MiniDLNA doesn't.
+/// Short enough for changed album art to show up soon.
+const DEFAULT_FRESHNESS: Duration = Duration::from_secs(5 * 60);
+
+/// Response headers kept along with the image, DLNA ones included.
+const KEPT_HEADERS: &[&str] = &[
+    "Content-Type",
+    "ETag",
+    "Last-Modified",
+    "Cache-Control",
+    "contentFeatures.dlna.org",
+    "transferMode.dlna.org",
+];
+
+/// Stored next to each image, as `<name>.toml`.
+#[derive(Clone, Serialize, Deserialize)]
+struct Metadata {
+    key: String,
+    headers: Vec<(String, String)>,
+    /// Seconds since the epoch.
+    fresh_until: u64,
+}
+
+impl Metadata {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_fresh(&self) -> bool {
+        unix_time(SystemTime::now()) < self.fresh_until
+    }
+}
+
+struct Entry {
+    metadata: Metadata,
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    size: u64,
+    clock: u64,
+}
+
+pub enum Lookup {
+    Miss,
+    Fresh(ResponseHead, Vec<u8>),
+    /// To be revalidated with the given conditional headers, the cached response standing if the image didn't change.
+    Stale(Vec<(&'static str, String)>, ResponseHead, Vec<u8>),
+}
+
+/// Small images fetched through the proxies, on disk so that they outlive restarts.
+pub struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+    state: Mutex<CacheState>,
+}
+
+impl ImageCache {
+    /// Opens the cache in `dir`, picking up the images cached by a previous run.
+    pub async fn open(dir: &Path, max_size: u64) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create image cache directory {:?}", dir))?;
+
+        let mut found = Vec::new();
+        let mut read_dir = fs::read_dir(dir)
+            .await
+            .with_context(|| format!("Failed to read image cache directory {:?}", dir))?;
+
+        while let Some(dir_entry) = read_dir.next_entry().await? {
+            let path = dir_entry.path();
+
+            //Left by a write that didn't complete.
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let _ = fs::remove_file(&path).await;
+                continue;
+            }
+
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+
+            match load_entry(&path).await {
+                Ok(entry) => found.push(entry),
+                Err(e) => {
+                    debug!(target: "dlnaproxy", "Dropping cached image {:?}: {:#}", path, e);
+                    let _ = fs::remove_file(&path).await;
+                    let _ = fs::remove_file(path.with_extension("")).await;
+                }
+            }
+        }
+
+        //Least recently used first, as the clock goes.
+        found.sort_by_key(|(_, _, used)| *used);
+
+        let mut state = CacheState::default();
+
+        for (metadata, size, _) in found {
+            state.clock += 1;
+            state.size += size;
+
+            state.entries.insert(
+                metadata.key.clone(),
+                Entry {
+                    metadata,
+                    size,
+                    last_used: state.clock,
+                },
+            );
+        }
+
+        info!(target: "dlnaproxy", "Image cache in {:?}: {} images, {} KiB.", dir, state.entries.len(), state.size / 1024);
+
+        let cache = ImageCache {
+            dir: dir.to_path_buf(),
+            max_size,
+            state: Mutex::new(state),
+        };
+
+        cache.evict(None).await;
+
+        Ok(cache)
+    }
+
+    /// Whether a request may be answered from the cache: a plain GET, for which a whole 200 response will do.
+    pub fn eligible(request: &RequestHead) -> bool {
+        request.method == "GET"
+            && ["Range", "If-None-Match", "If-Modified-Since"]
+                .iter()
+                .all(|header| request.headers.get(header).is_none())
+    }
+
+    /// Whether a response to an eligible request is an image worth keeping.
+    pub fn cacheable(response: &ResponseHead) -> bool {
+        let is_image = response
+            .headers
+            .get_str("Content-Type")
+            .is_some_and(|content_type| content_type.trim_start().starts_with("image/"));
+
+        let small = response
+            .headers
+            .get_str("Content-Length")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .is_some_and(|length| length <= MAX_IMAGE_SIZE);
+
+        response.status == 200
+            && is_image
+            && small
+            && !response.headers.has_token("Cache-Control", "no-store")
+    }
+
+    pub async fn lookup(&self, key: &str) -> Lookup {
+        let metadata = {
+            let mut state = self.state.lock().unwrap();
+
+            state.clock += 1;
+            let clock = state.clock;
+
+            match state.entries.get_mut(key) {
+                Some(entry) => {
+                    entry.last_used = clock;
+                    entry.metadata.clone()
+                }
+                None => return Lookup::Miss,
+            }
+        };
+
+        let body = match fs::read(self.body_path(key)).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Failed to read cached image: {}", e);
+                self.remove(key).await;
+                return Lookup::Miss;
+            }
+        };
+
+        let response = response_from(&metadata, body.len());
+
+        if metadata.is_fresh() {
+            return Lookup::Fresh(response, body);
+        }
+
+        let validators: Vec<(&'static str, String)> = [
+            ("If-None-Match", metadata.header("ETag")),
+            ("If-Modified-Since", metadata.header("Last-Modified")),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?.to_string())))
+        .collect();
+
+        match validators.is_empty() {
+            true => Lookup::Miss,
+            false => Lookup::Stale(validators, response, body),
+        }
+    }
+
+    /// Keeps the body of a cacheable response.
+    pub async fn store(&self, key: &str, response: &ResponseHead, body: &[u8]) {
+        let size = body.len() as u64;
+
+        if size > self.max_size {
+            return;
+        }
+
+        let metadata = Metadata {
+            key: key.into(),
+            headers: KEPT_HEADERS
+                .iter()
+                .filter_map(|name| Some((name.to_string(), response.headers.get_str(name)?.into())))
+                .collect(),
+            fresh_until: fresh_until(response.headers.get_str("Cache-Control")),
+        };
+
+        if let Err(e) = self.write(&metadata, Some(body)).await {
+            warn!(target: "dlnaproxy", "Failed to cache image: {:#}", e);
+            return;
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            state.clock += 1;
+            state.size += size;
+
+            let last_used = state.clock;
+            let previous = state.entries.insert(
+                key.into(),
+                Entry {
+                    metadata,
+                    size,
+                    last_used,
+                },
+            );
+
+            if let Some(previous) = previous {
+                state.size -= previous.size;
+            }
+        }
+
+        self.evict(Some(key)).await;
+    }
+
+    /// The remote server told us (304) that the cached image is still good.
+    pub async fn revalidated(&self, key: &str, response: &ResponseHead) {
+        let metadata = {
+            let mut state = self.state.lock().unwrap();
+
+            let Some(entry) = state.entries.get_mut(key) else {
+                return;
+            };
+
+            //A 304 need not repeat the Cache-Control of the image it stands for.
+            if let Some(cache_control) = response.headers.get_str("Cache-Control") {
+                entry
+                    .metadata
+                    .headers
+                    .retain(|(name, _)| name != "Cache-Control");
+                entry
+                    .metadata
+                    .headers
+                    .push(("Cache-Control".into(), cache_control.into()));
+            }
+
+            entry.metadata.fresh_until = fresh_until(entry.metadata.header("Cache-Control"));
+
+            if let Some(etag) = response.headers.get_str("ETag") {
+                entry.metadata.headers.retain(|(name, _)| name != "ETag");
+                entry.metadata.headers.push(("ETag".into(), etag.into()));
+            }
+
+            entry.metadata.clone()
+        };
+
+        if let Err(e) = self.write(&metadata, None).await {
+            warn!(target: "dlnaproxy", "Failed to update cached image: {:#}", e);
+        }
+    }
+
+    pub async fn remove(&self, key: &str) {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let removed = state.entries.remove(key);
+
+            if let Some(entry) = &removed {
+                state.size -= entry.size;
+            }
+
+            removed
+        };
+
+        if removed.is_some() {
+            let _ = fs::remove_file(self.metadata_path(key)).await;
+            let _ = fs::remove_file(self.body_path(key)).await;
+        }
+    }
+
+    /// Drops the least recently used images until the cache fits, but for the `protected` one.
+    async fn evict(&self, protected: Option<&str>) {
+        loop {
+            let lru = {
+                let state = self.state.lock().unwrap();
+
+                if state.size <= self.max_size {
+                    return;
+                }
+
+                state
+                    .entries
+                    .iter()
+                    .filter(|(key, _)| Some(key.as_str()) != protected)
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone())
+            };
+
+            match lru {
+                Some(key) => self.remove(&key).await,
+                None => return,
+            }
+        }
+    }
+
+    async fn write(&self, metadata: &Metadata, body: Option<&[u8]>) -> Result<()> {
+        let key = &metadata.key;
+
+        if let Some(body) = body {
+            write_atomically(&self.body_path(key), body).await?;
+        }
+
+        let metadata = toml::to_string(metadata).context("Failed to serialize image metadata.")?;
+
+        write_atomically(&self.metadata_path(key), metadata.as_bytes()).await
+    }
+
+    fn body_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(Uuid::new_v5(&FILE_NAMESPACE, key.as_bytes()).to_string())
+    }
+
+    fn metadata_path(&self, key: &str) -> PathBuf {
+        self.body_path(key).with_extension("toml")
+    }
+}
+
+/// Metadata, body size and last use of a cached image, as far as the time it was stored tells.
+async fn load_entry(path: &Path) -> Result<(Metadata, u64, SystemTime)> {
+    let metadata: Metadata = toml::from_str(&fs::read_to_string(path).await?)?;
+
+    let body = fs::metadata(path.with_extension(""))
+        .await
+        .context("Image missing.")?;
+
+    Ok((metadata, body.len(), body.modified()?))
+}
+
+/// Concurrent writes of the same file each go through a temporary file of their own.
+async fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let tmp = PathBuf::from(tmp);
+
+    if let Err(e) = fs::write(&tmp, content).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e).with_context(|| format!("Failed to write {:?}", tmp));
+    }
+
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+fn response_from(metadata: &Metadata, length: usize) -> ResponseHead {
+    let mut response = ResponseHead::new(200, "OK");
+
+    for (name, value) in &metadata.headers {
+        response.headers.set(name, value.as_str());
+    }
+
+    response.headers.set("Content-Length", length.to_string());
+
+    response
+}
+
+/// Until when an image may be served without asking the remote server, as its `Cache-Control` header tells.
+fn fresh_until(cache_control: Option<&str>) -> u64 {
+    let directives = || cache_control.into_iter().flat_map(|value| value.split(','));
+
+    //Cached, but to be revalidated every time.
+    if directives().any(|directive| directive.trim().eq_ignore_ascii_case("no-cache")) {
+        return 0;
+    }
+
+    let max_age = directives()
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|seconds| seconds.trim().parse().ok())
+        .map_or(DEFAULT_FRESHNESS, Duration::from_secs);
+
+    //max-age comes from the remote server, however large it may be.
+    unix_time(SystemTime::now()).saturating_add(max_age.as_secs())
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
@@ -0,0 +1,415 @@
+use std::io;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt as _, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _,
+};
+
+use httparse::{Request, Response, Status, EMPTY_HEADER};
+
+/// Largest request/response head we accept, headers included.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 64;
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[derive(Clone, Default)]
+pub struct Headers(Vec<(String, Vec<u8>)>);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .map(str::trim)
+    }
+
+    /// Whether a comma separated header, such as Connection, contains `token`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Vec<u8>> {
+        let mut removed = None;
+
+        self.0.retain_mut(|(n, value)| {
+            if n.eq_ignore_ascii_case(name) {
+                removed = Some(std::mem::take(value));
+                false
+            } else {
+                true
+            }
+        });
+
+        removed
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        let value = value.into();
+
+        match self
+            .0
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, v)) => *v = value,
+            None => self.0.push((name.into(), value)),
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+
+    fn from_parsed(parsed: &[httparse::Header<'_>]) -> Self {
+        Headers(
+            parsed
+                .iter()
+                .take_while(|h| !h.name.is_empty())
+                .map(|h| (h.name.to_string(), h.value.to_vec()))
+                .collect(),
+        )
+    }
+}
+
+/// How the body following a head is delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+fn body_length_from(headers: &Headers, otherwise: BodyLength) -> io::Result<BodyLength> {
+    if headers.has_token("Transfer-Encoding", "chunked") {
+        return Ok(BodyLength::Chunked);
+    }
+
+    match headers.get_str("Content-Length") {
+        Some(length) => length
+            .parse()
+            .map(BodyLength::Fixed)
+            .map_err(|_| invalid_data(format!("Bad Content-Length: '{}'", length))),
+        None => Ok(otherwise),
+    }
+}
+
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: u8,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    pub fn parse(head: &[u8]) -> io::Result<Self> {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+        let mut req = Request::new(&mut headers);
+
+        match req.parse(head) {
+            Ok(Status::Complete(_)) => {}
+            Ok(Status::Partial) => return Err(invalid_data("Incomplete HTTP request head.")),
+            Err(e) => return Err(invalid_data(format!("Bad HTTP request: {}", e))),
+        }
+
+        Ok(RequestHead {
+            method: req.method.unwrap_or_default().to_string(),
+            target: req.path.unwrap_or_default().to_string(),
+            version: req.version.unwrap_or(1),
+            headers: Headers::from_parsed(req.headers),
+        })
+    }
+
+    /// Path and query of the request target, even when sent in absolute form.
+    pub fn path(&self) -> &str {
+        match self.target.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => &self.target,
+        }
+    }
+
+    pub fn body_length(&self) -> io::Result<BodyLength> {
+        body_length_from(&self.headers, BodyLength::Empty)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            0 => self.headers.has_token("Connection", "keep-alive"),
+            _ => !self.headers.has_token("Connection", "close"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!(
+            "{} {} HTTP/1.{}\r\n",
+            self.method, self.target, self.version
+        )
+        .into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+#[derive(Clone)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn parse(head: &[u8]) -> io::Result<Self> {
+        let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+        let mut resp = Response::new(&mut headers);
+
+        match resp.parse(head) {
+            Ok(Status::Complete(_)) => {}
+            Ok(Status::Partial) => return Err(invalid_data("Incomplete HTTP response head.")),
+            Err(e) => return Err(invalid_data(format!("Bad HTTP response: {}", e))),
+        }
+
+        Ok(ResponseHead {
+            version: resp.version.unwrap_or(1),
+            status: resp.code.unwrap_or_default(),
+            reason: resp.reason.unwrap_or_default().to_string(),
+            headers: Headers::from_parsed(resp.headers),
+        })
+    }
+
+    pub fn new(status: u16, reason: &str) -> Self {
+        ResponseHead {
+            version: 1,
+            status,
+            reason: reason.into(),
+            headers: Headers::default(),
+        }
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status)
+    }
+
+    pub fn body_length(&self, request_method: &str) -> io::Result<BodyLength> {
+        if request_method.eq_ignore_ascii_case("HEAD")
+            || self.is_informational()
+            || self.status == 204
+            || self.status == 304
+        {
+            return Ok(BodyLength::Empty);
+        }
+
+        body_length_from(&self.headers, BodyLength::UntilClose)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            0 => self.headers.has_token("Connection", "keep-alive"),
+            _ => !self.headers.has_token("Connection", "close"),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!(
+            "HTTP/1.{} {} {}\r\n",
+            self.version, self.status, self.reason
+        )
+        .into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// Reads a message head up to (and including) the empty line, `None` on a clean EOF.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+
+    loop {
+        let limit = (MAX_HEAD_SIZE - head.len()) as u64;
+        let read = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut head)
+            .await?;
+
+        if read == 0 {
+            return match head.is_empty() {
+                true => Ok(None),
+                false => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+
+        //Tolerate empty lines ahead of the start line.
+        if head == b"\r\n" || head == b"\n" {
+            head.clear();
+            continue;
+        }
+
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            return Ok(Some(head));
+        }
+
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(invalid_data("HTTP head too large."));
+        }
+    }
+}
+
+async fn read_chunk_size<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<u64> {
+    line.clear();
+    (&mut *reader).take(1024).read_until(b'\n', line).await?;
+
+    let size = std::str::from_utf8(line)
+        .ok()
+        .and_then(|l| l.split(';').next())
+        .map(str::trim)
+        .ok_or_else(|| invalid_data("Bad chunk size."))?;
+
+    u64::from_str_radix(size, 16).map_err(|_| invalid_data(format!("Bad chunk size: '{}'", size)))
+}
+
+/// Relays a body as is, chunked framing included.
+pub async fn copy_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    length: BodyLength,
+) -> io::Result<()> {
+    match length {
+        BodyLength::Empty => {}
+        BodyLength::Fixed(n) => {
+            if tokio::io::copy(&mut (&mut *reader).take(n), writer).await? < n {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        BodyLength::Chunked => {
+            let mut line = Vec::new();
+
+            loop {
+                let size = read_chunk_size(reader, &mut line).await?;
+                writer.write_all(&line).await?;
+
+                if size == 0 {
+                    break;
+                }
+
+                //Chunk data and its trailing CRLF.
+                if tokio::io::copy(&mut (&mut *reader).take(size + 2), writer).await? < size + 2 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+
+            //Trailers, up to the final empty line.
+            loop {
+                line.clear();
+                if (&mut *reader)
+                    .take(8192)
+                    .read_until(b'\n', &mut line)
+                    .await?
+                    == 0
+                {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                writer.write_all(&line).await?;
+
+                if line == b"\r\n" || line == b"\n" {
+                    break;
+                }
+            }
+        }
+        BodyLength::UntilClose => {
+            tokio::io::copy(reader, writer).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a whole body in memory, decoding chunked framing, provided it fits in `limit` bytes.
+pub async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    length: BodyLength,
+    limit: u64,
+) -> io::Result<Vec<u8>> {
+    let too_large = || invalid_data("HTTP body too large to be rewritten.");
+
+    let mut body = Vec::new();
+
+    match length {
+        BodyLength::Empty => {}
+        BodyLength::Fixed(n) if n > limit => return Err(too_large()),
+        BodyLength::Fixed(n) => {
+            body.reserve(n as usize);
+            if (&mut *reader).take(n).read_to_end(&mut body).await? < n as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        BodyLength::Chunked => {
+            let mut line = Vec::new();
+
+            loop {
+                let size = read_chunk_size(reader, &mut line).await?;
+
+                if size == 0 {
+                    break;
+                }
+
+                if body.len() as u64 + size > limit {
+                    return Err(too_large());
+                }
+
+                (&mut *reader).take(size).read_to_end(&mut body).await?;
+
+                line.clear();
+                (&mut *reader).take(2).read_until(b'\n', &mut line).await?;
+            }
+
+            //Trailers are dropped, we re-frame the body with a Content-Length.
+            loop {
+                line.clear();
+                if (&mut *reader)
+                    .take(8192)
+                    .read_until(b'\n', &mut line)
+                    .await?
+                    == 0
+                    || line == b"\r\n"
+                    || line == b"\n"
+                {
+                    break;
+                }
+            }
+        }
+        BodyLength::UntilClose => {
+            if (&mut *reader)
+                .take(limit + 1)
+                .read_to_end(&mut body)
+                .await? as u64
+                > limit
+            {
+                return Err(too_large());
+            }
+        }
+    }
+
+    Ok(body)
+}
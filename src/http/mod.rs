@@ -0,0 +1,527 @@
+use log::{debug, trace, warn};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{io, net::SocketAddr, sync::Arc};
+
+use tokio::io::{AsyncWriteExt as _, BufReader};
+use tokio::net::{
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+    TcpStream,
+};
+
+use reqwest::Url;
+
+use description::element_text;
+use gena::Subscriptions;
+use image_cache::{Lookup, MAX_IMAGE_SIZE};
+use message::{copy_body, read_body, read_head, BodyLength, RequestHead, ResponseHead};
+use rewrite::UrlRewriter;
+use soap::SoapAction;
+
+pub use browse_cache::BrowseCache;
+pub use content_directory::{Aggregator, Backend, ContentDirectory, Forwarder};
+pub use description::{virtual_udn, DescriptionOverrides};
+pub use device::{server_header, DESCRIPTION_PATH};
+pub use image_cache::ImageCache;
+pub use server::MediaServer;
+
+mod browse_cache;
+mod content_directory;
+mod description;
+mod device;
+mod gena;
+mod image_cache;
+mod message;
+mod rewrite;
+mod server;
+mod soap;
+
+/// Rewritten documents are buffered in memory, don't bother with anything larger.
+const MAX_REWRITTEN_BODY: u64 = 16 * 1024 * 1024;
+
+const MAX_EVENT_BODY: u64 = 1024 * 1024;
+
+/// Browse/Search requests are read whole when cached, they are small.
+const MAX_SOAP_REQUEST_BODY: u64 = 64 * 1024;
+
+/// Responses we don't forward as is.
+#[derive(Clone, Copy, Debug)]
+enum Interception {
+    /// The root device description, whose URLs must point at the proxy.
+    Description,
+    /// ContentDirectory Browse/Search responses, whose DIDL-Lite result lists media URLs.
+    BrowseResult,
+}
+
+/// What the HTTP-aware proxy needs to know about the remote server.
+pub struct HttpContext {
+    description_path: String,
+    rewriter: UrlRewriter,
+    subscriptions: Subscriptions,
+    overrides: DescriptionOverrides,
+    browse_cache: Option<BrowseCache>,
+    image_cache: Option<Arc<ImageCache>>,
+    /// Whether subscriptions are relayed, which takes the remote server being able to reach us.
+    events: bool,
+    /// Whether we said that the remote server can't reach the proxy, subscriptions being passed on as is.
+    unreachable_warned: AtomicBool,
+}
+
+impl HttpContext {
+    pub fn new(
+        description_url: &Url,
+        remote_addr: SocketAddr,
+        overrides: DescriptionOverrides,
+        browse_cache: Option<BrowseCache>,
+        image_cache: Option<Arc<ImageCache>>,
+    ) -> Self {
+        let description_path = match description_url.query() {
+            Some(query) => format!("{}?{}", description_url.path(), query),
+            None => description_url.path().to_string(),
+        };
+
+        HttpContext {
+            description_path,
+            rewriter: UrlRewriter::new(description_url, remote_addr),
+            subscriptions: Subscriptions::default(),
+            overrides,
+            browse_cache,
+            image_cache,
+            events: true,
+            unreachable_warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Refuses subscriptions, for remote servers that can't send us their events.
+    pub fn without_events(mut self) -> Self {
+        self.events = false;
+        self
+    }
+
+    fn interception_for(&self, request: &RequestHead) -> Option<Interception> {
+        let method = request.method.as_str();
+
+        if (method == "GET" || method == "HEAD") && request.path() == self.description_path {
+            return Some(Interception::Description);
+        }
+
+        let soap_action = request
+            .headers
+            .get_str("SOAPACTION")
+            .and_then(SoapAction::parse);
+
+        if let Some(action) = soap_action {
+            if method == "POST"
+                && action.is_service("ContentDirectory")
+                && (action.action == "Browse" || action.action == "Search")
+            {
+                return Some(Interception::BrowseResult);
+            }
+        }
+
+        None
+    }
+
+    fn rewrite(&self, interception: Interception, body: Vec<u8>, proxy_authority: &str) -> Vec<u8> {
+        let text = match String::from_utf8(body) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "{:?} isn't valid UTF-8, forwarding it untouched.", interception);
+                return e.into_bytes();
+            }
+        };
+
+        match interception {
+            Interception::Description => {
+                if let Some(url_base) = element_text(&text, "URLBase") {
+                    self.rewriter.learn(url_base);
+                }
+
+                let rewritten = self.rewriter.rewrite(&text, proxy_authority);
+
+                match self.overrides.is_empty() {
+                    true => rewritten.into_owned().into_bytes(),
+                    false => self.overrides.apply(&rewritten).into_bytes(),
+                }
+            }
+            Interception::BrowseResult => {
+                let rewritten = soap::rewrite_argument(&text, "Result", |didl| {
+                    self.rewriter.rewrite(didl, proxy_authority).into_owned()
+                });
+
+                match rewritten {
+                    Ok(rewritten) => rewritten.into_bytes(),
+                    Err(e) => {
+                        warn!(target: "dlnaproxy", "Couldn't rewrite ContentDirectory response: {}", e);
+                        text.into_bytes()
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Upstream {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Upstream {
+    async fn connect(origin: SocketAddr) -> io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(origin).await?.into_split();
+
+        Ok(Upstream {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+}
+
+/// Proxies HTTP/1.x requests from `client` to `origin`, one at a time, rewriting the responses we intercept.
+/// `bound` is the address the proxy listens on.
+pub async fn handle_conn(
+    client: TcpStream,
+    origin: SocketAddr,
+    bound: SocketAddr,
+    context: &HttpContext,
+) -> io::Result<()> {
+    let local_addr = client.local_addr()?;
+    let peer_addr = client.peer_addr()?;
+
+    let (client_reader, mut client_writer) = client.into_split();
+    let mut client_reader = BufReader::new(client_reader);
+
+    let mut upstream: Option<Upstream> = None;
+
+    while let Some(head) = read_head(&mut client_reader).await? {
+        let mut request = RequestHead::parse(&head)?;
+
+        trace!(target: "dlnaproxy", "{} {}", request.method, request.target);
+
+        let request_body = request.body_length()?;
+        let client_keep_alive = request.keep_alive();
+
+        //Events sent by the remote server to the callback we substituted, not meant for it.
+        if request.method == "NOTIFY" {
+            if let Some(token) = request.path().strip_prefix(gena::EVENT_PATH_PREFIX) {
+                let body = read_body(&mut client_reader, request_body, MAX_EVENT_BODY).await?;
+
+                //Only events of a subscription we relay may drop cached Browse responses.
+                if let Some(cache) = &context.browse_cache {
+                    if context.subscriptions.knows(token, &request) {
+                        cache.on_event(&body);
+                    }
+                }
+
+                let (status, reason) = context.subscriptions.relay(token, &request, &body).await;
+
+                let mut response = ResponseHead::new(status, reason);
+                response.headers.set("Content-Length", "0");
+
+                client_writer.write_all(&response.to_bytes()).await?;
+                client_writer.flush().await?;
+
+                if !client_keep_alive {
+                    break;
+                }
+
+                continue;
+            }
+        }
+
+        if request.method == "SUBSCRIBE" && !context.events {
+            read_body(&mut client_reader, request_body, MAX_EVENT_BODY).await?;
+
+            debug!(target: "dlnaproxy", "Refused a subscription from {}, events can't be relayed.", peer_addr);
+
+            let mut response = ResponseHead::new(501, "Not Implemented");
+            response.headers.set("Content-Length", "0");
+
+            client_writer.write_all(&response.to_bytes()).await?;
+            client_writer.flush().await?;
+
+            if !client_keep_alive {
+                break;
+            }
+
+            continue;
+        }
+
+        let interception = context.interception_for(&request);
+
+        //The description is rewritten, so a HEAD can only be answered with the length of the rewritten one.
+        let head_as_get = interception.is_some() && request.method == "HEAD";
+
+        if head_as_get {
+            request.method = "GET".into();
+        }
+
+        //We relay the body right away, the client may well be waiting for a go-ahead.
+        if request.headers.remove("Expect").is_some() {
+            client_writer
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await?;
+        }
+
+        //We can't rewrite compressed documents.
+        if interception.is_some() {
+            request.headers.remove("Accept-Encoding");
+        }
+
+        let proxy_authority = request
+            .headers
+            .get_str("Host")
+            .map(String::from)
+            .unwrap_or_else(|| local_addr.to_string());
+
+        //Cached Browse/Search requests are read whole, to be looked up.
+        let mut cache_key = None;
+        let mut buffered_body = None;
+
+        if let (Some(Interception::BrowseResult), Some(cache)) =
+            (interception, &context.browse_cache)
+        {
+            let body = read_body(&mut client_reader, request_body, MAX_SOAP_REQUEST_BODY).await?;
+
+            if let Some(action) = request
+                .headers
+                .get_str("SOAPACTION")
+                .and_then(SoapAction::parse)
+            {
+                cache_key = BrowseCache::key(request.path(), &action, &body);
+
+                let origin_url = format!("http://{}{}", origin, request.target);
+
+                let cached = match &cache_key {
+                    Some(key) => cache.get(key, &origin_url, &action).await,
+                    None => None,
+                };
+
+                if let Some((mut response, body)) = cached {
+                    let body = context.rewrite(Interception::BrowseResult, body, &proxy_authority);
+
+                    debug!(target: "dlnaproxy", "Served cached {} to {}.", action.action, peer_addr);
+
+                    response
+                        .headers
+                        .set("Content-Length", body.len().to_string());
+
+                    client_writer.write_all(&response.to_bytes()).await?;
+                    client_writer.write_all(&body).await?;
+                    client_writer.flush().await?;
+
+                    if !client_keep_alive {
+                        break;
+                    }
+
+                    continue;
+                }
+            }
+
+            //Re-framed, should it have been chunked.
+            request.headers.remove("Transfer-Encoding");
+            request
+                .headers
+                .set("Content-Length", body.len().to_string());
+
+            buffered_body = Some(body);
+        }
+
+        //Images are looked up by the URL they are fetched from.
+        let image_cache = match (&context.image_cache, interception) {
+            (Some(cache), None) if ImageCache::eligible(&request) => {
+                Some((cache, format!("{}{}", origin, request.target)))
+            }
+            _ => None,
+        };
+
+        //The cached image to fall back on, should the remote server tell us it didn't change.
+        let mut revalidating = None;
+
+        if let Some((cache, key)) = &image_cache {
+            match cache.lookup(key).await {
+                Lookup::Miss => {}
+                Lookup::Fresh(response, body) => {
+                    trace!(target: "dlnaproxy", "Served cached image to {}.", peer_addr);
+
+                    client_writer.write_all(&response.to_bytes()).await?;
+                    client_writer.write_all(&body).await?;
+                    client_writer.flush().await?;
+
+                    if !client_keep_alive {
+                        break;
+                    }
+
+                    continue;
+                }
+                Lookup::Stale(validators, response, body) => {
+                    for (name, value) in validators {
+                        request.headers.set(name, value);
+                    }
+
+                    revalidating = Some((response, body));
+                }
+            }
+        }
+
+        let up = match upstream.as_mut() {
+            Some(up) => up,
+            None => upstream.insert(Upstream::connect(origin).await?),
+        };
+
+        //The remote server can't reach LAN callbacks, it will send its events to us instead, provided the proxy
+        //listens on the address we reach it from.
+        let callback_addr = SocketAddr::new(up.writer.local_addr()?.ip(), local_addr.port());
+        let reachable = bound.ip().is_unspecified() || bound.ip() == callback_addr.ip();
+
+        let subscription = match request.method.as_str() {
+            "SUBSCRIBE" if !reachable => {
+                if !context.unreachable_warned.swap(true, Ordering::Relaxed) {
+                    warn!(target: "dlnaproxy", "The remote server can't send events to the proxy on {}, as it reaches us on {}: passing subscriptions on as is. Bind the proxy to a wildcard address for events to be relayed.", bound, callback_addr.ip());
+                }
+
+                None
+            }
+            "SUBSCRIBE" => request
+                .headers
+                .get_str("CALLBACK")
+                .and_then(|callback| context.subscriptions.register(callback)),
+            _ => None,
+        };
+
+        if let Some(token) = &subscription {
+            request
+                .headers
+                .set("CALLBACK", gena::callback_header(callback_addr, token));
+        }
+
+        let exchange = async {
+            up.writer.write_all(&request.to_bytes()).await?;
+
+            match &buffered_body {
+                Some(body) => up.writer.write_all(body).await?,
+                None => copy_body(&mut client_reader, &mut up.writer, request_body).await?,
+            }
+
+            up.writer.flush().await?;
+
+            loop {
+                let head = read_head(&mut up.reader)
+                    .await?
+                    .ok_or(io::ErrorKind::UnexpectedEof)?;
+                let response = ResponseHead::parse(&head)?;
+
+                if !response.is_informational() {
+                    break io::Result::Ok(response);
+                }
+            }
+        };
+
+        let mut response = match exchange.await {
+            Ok(response) => response,
+            Err(e) => {
+                if let Some(token) = &subscription {
+                    context.subscriptions.forget(token);
+                }
+
+                return Err(e);
+            }
+        };
+
+        match request.method.as_str() {
+            "SUBSCRIBE" => context.subscriptions.on_subscribe_response(
+                subscription.as_deref(),
+                request.headers.get_str("SID"),
+                &response,
+            ),
+            "UNSUBSCRIBE" if response.status == 200 => {
+                if let Some(sid) = request.headers.get_str("SID") {
+                    context.subscriptions.unsubscribe(sid);
+                }
+            }
+            _ => {}
+        }
+
+        let response_body = response.body_length(&request.method)?;
+        let upstream_keep_alive = response.keep_alive() && response_body != BodyLength::UntilClose;
+
+        let mut client_close = !client_keep_alive;
+
+        match interception {
+            Some(interception) if response.status == 200 => {
+                let body = read_body(&mut up.reader, response_body, MAX_REWRITTEN_BODY).await?;
+
+                if let (Some(key), Some(cache)) = (cache_key, &context.browse_cache) {
+                    let mut cached = response.clone();
+                    cached.headers.remove("Transfer-Encoding");
+                    cached.headers.remove("Connection");
+
+                    cache.insert(key, cached, body.clone());
+                }
+
+                let body = context.rewrite(interception, body, &proxy_authority);
+
+                debug!(target: "dlnaproxy", "Rewrote {:?} served to {}.", interception, peer_addr);
+
+                response.headers.remove("Transfer-Encoding");
+                response
+                    .headers
+                    .set("Content-Length", body.len().to_string());
+
+                client_writer.write_all(&response.to_bytes()).await?;
+
+                if !head_as_get {
+                    client_writer.write_all(&body).await?;
+                }
+            }
+            _ if head_as_get => {
+                client_writer.write_all(&response.to_bytes()).await?;
+                copy_body(&mut up.reader, &mut tokio::io::sink(), response_body).await?;
+            }
+            None if revalidating.is_some() && response.status == 304 => {
+                let (cached, body) = revalidating.take().unwrap();
+
+                if let Some((cache, key)) = &image_cache {
+                    cache.revalidated(key, &response).await;
+                }
+
+                client_writer.write_all(&cached.to_bytes()).await?;
+                client_writer.write_all(&body).await?;
+            }
+            None if image_cache.is_some() && ImageCache::cacheable(&response) => {
+                let body = read_body(&mut up.reader, response_body, MAX_IMAGE_SIZE).await?;
+
+                if let Some((cache, key)) = &image_cache {
+                    cache.store(key, &response, &body).await;
+                }
+
+                client_writer.write_all(&response.to_bytes()).await?;
+                client_writer.write_all(&body).await?;
+            }
+            _ => {
+                //Gone or no longer an image we keep.
+                if let (Some((cache, key)), Some(_)) = (&image_cache, &revalidating) {
+                    cache.remove(key).await;
+                }
+
+                client_writer.write_all(&response.to_bytes()).await?;
+                copy_body(&mut up.reader, &mut client_writer, response_body).await?;
+
+                //The client can only tell where the body ends when we close the connection.
+                client_close |= response_body == BodyLength::UntilClose;
+            }
+        }
+
+        client_writer.flush().await?;
+
+        if !upstream_keep_alive {
+            upstream = None;
+        }
+
+        if client_close {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use reqwest::Url;
+
+/// Characters ending the authority part of a URL embedded in XML or text.
+const AUTHORITY_END: &[char] = &[
+    '/', '?', '#', '<', '>', '"', '\'', ' ', '\t', '\r', '\n', '&',
+];
+
+/// `host:port`, lowercased and with the default HTTP port made explicit.
+fn normalize_authority(authority: &str) -> Option<String> {
+    let authority = authority.trim().to_ascii_lowercase();
+
+    if authority.is_empty() {
+        return None;
+    }
+
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
+    };
+
+    Some(match has_port {
+        true => authority,
+        false => format!("{}:80", authority),
+    })
+}
+
+/// Rewrites absolute URLs pointing at the remote server so that they go through the proxy.
+pub struct UrlRewriter {
+    remote_authorities: RwLock<Vec<String>>,
+}
+
+impl UrlRewriter {
+    pub fn new(remote_url: &Url, remote_addr: SocketAddr) -> Self {
+        let rewriter = UrlRewriter {
+            remote_authorities: RwLock::new(Vec::new()),
+        };
+
+        rewriter.learn(remote_url.as_str());
+        rewriter.learn_authority(&remote_addr.to_string());
+
+        rewriter
+    }
+
+    /// Also consider `url`'s host as the remote server's, e.g. an URLBase using another of its addresses.
+    pub fn learn(&self, url: &str) {
+        if let Ok(url) = Url::parse(url) {
+            if let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) {
+                self.learn_authority(&format!("{}:{}", host, port));
+            }
+        }
+    }
+
+    fn learn_authority(&self, authority: &str) {
+        let Some(authority) = normalize_authority(authority) else {
+            return;
+        };
+
+        let mut authorities = self.remote_authorities.write().unwrap();
+
+        if !authorities.contains(&authority) {
+            authorities.push(authority);
+        }
+    }
+
+    pub fn is_remote(&self, authority: &str) -> bool {
+        normalize_authority(authority)
+            .is_some_and(|a| self.remote_authorities.read().unwrap().contains(&a))
+    }
+
+    /// Replaces the authority of every `http://` URL found in `text` that points at the remote server.
+    pub fn rewrite<'a>(&self, text: &'a str, proxy_authority: &str) -> Cow<'a, str> {
+        const SCHEME: &str = "http://";
+
+        let mut rewritten = String::new();
+        let mut copied = 0;
+        let mut search_from = 0;
+
+        while let Some(found) = text[search_from..].find(SCHEME) {
+            let authority_start = search_from + found + SCHEME.len();
+            let authority_end = text[authority_start..]
+                .find(AUTHORITY_END)
+                .map_or(text.len(), |i| authority_start + i);
+
+            if self.is_remote(&text[authority_start..authority_end]) {
+                rewritten.push_str(&text[copied..authority_start]);
+                rewritten.push_str(proxy_authority);
+                copied = authority_end;
+            }
+
+            search_from = authority_end;
+        }
+
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+
+        rewritten.push_str(&text[copied..]);
+        Cow::Owned(rewritten)
+    }
+}
@@ -0,0 +1,263 @@
+use log::{debug, info, trace, warn};
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::io::{AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use super::content_directory::{ActionOutput, ContentDirectory};
+use super::description::DescriptionOverrides;
+use super::device::{self, CONNECTION_MANAGER, CONTENT_DIRECTORY};
+use super::message::{read_body, read_head, RequestHead, ResponseHead};
+use super::soap::{self, Fault, SoapAction};
+
+/// SOAP requests are small, anything bigger isn't meant for us.
+const MAX_REQUEST_BODY: u64 = 64 * 1024;
+
+/// The MediaServer dlnaproxy serves itself: a generated description and a ContentDirectory backed by remote servers.
+pub struct MediaServer {
+    udn: String,
+    overrides: DescriptionOverrides,
+    content_directory: ContentDirectory,
+}
+
+impl MediaServer {
+    pub fn new(
+        udn: &str,
+        overrides: DescriptionOverrides,
+        content_directory: ContentDirectory,
+    ) -> Self {
+        MediaServer {
+            udn: udn.into(),
+            overrides,
+            content_directory,
+        }
+    }
+
+    /// Generated on each request, as a forwarded server's name comes from its (cached) description.
+    async fn description(&self) -> String {
+        let name = self.content_directory.name().await;
+        let description = device::root_description(&name, &self.udn);
+
+        match self.overrides.is_empty() {
+            true => description,
+            false => self.overrides.apply(&description),
+        }
+    }
+
+    /// Binds `addr` and serves requests until `shutdown` is triggered.
+    pub async fn start(
+        self,
+        addr: SocketAddr,
+        shutdown: CancellationToken,
+    ) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Unable to bind media server address {}", addr))?;
+
+        info!(target: "dlnaproxy", "Serving our own media server on {}.", addr);
+
+        Ok(tokio::spawn(Arc::new(self).listen_loop(listener, shutdown)))
+    }
+
+    async fn listen_loop(self: Arc<Self>, listener: TcpListener, shutdown: CancellationToken) {
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!(target: "dlnaproxy", "Failed to accept media server connection: {}", err);
+                        continue;
+                    }
+                },
+            };
+
+            let server = self.clone();
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = shutdown.cancelled() => {}
+                    result = server.handle_conn(stream) => {
+                        if let Err(err) = result {
+                            debug!(target: "dlnaproxy", "Media server connection with {} ended: {}", peer_addr, err);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    async fn handle_conn(&self, stream: TcpStream) -> io::Result<()> {
+        let local_addr = stream.local_addr()?;
+
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        while let Some(head) = read_head(&mut reader).await? {
+            let request = RequestHead::parse(&head)?;
+
+            trace!(target: "dlnaproxy", "{} {}", request.method, request.target);
+
+            let body = read_body(&mut reader, request.body_length()?, MAX_REQUEST_BODY).await?;
+
+            let host = request
+                .headers
+                .get_str("Host")
+                .map(String::from)
+                .unwrap_or_else(|| local_addr.to_string());
+
+            let (mut response, content) = self.respond(&request, &body, &host).await;
+
+            response.headers.set("Server", device::server_header());
+            response
+                .headers
+                .set("Content-Length", content.len().to_string());
+
+            writer.write_all(&response.to_bytes()).await?;
+
+            if request.method != "HEAD" {
+                writer.write_all(content.as_bytes()).await?;
+            }
+
+            writer.flush().await?;
+
+            if !request.keep_alive() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn respond(
+        &self,
+        request: &RequestHead,
+        body: &[u8],
+        host: &str,
+    ) -> (ResponseHead, String) {
+        match (request.method.as_str(), request.path()) {
+            ("GET" | "HEAD", device::DESCRIPTION_PATH) => xml(self.description().await),
+            ("GET" | "HEAD", device::CONTENT_DIRECTORY_SCPD_PATH) => {
+                xml(device::CONTENT_DIRECTORY_SCPD.into())
+            }
+            ("GET" | "HEAD", device::CONNECTION_MANAGER_SCPD_PATH) => {
+                xml(device::CONNECTION_MANAGER_SCPD.into())
+            }
+            ("POST", device::CONTENT_DIRECTORY_CONTROL_PATH) => {
+                self.control(request, body, CONTENT_DIRECTORY, host).await
+            }
+            ("POST", device::CONNECTION_MANAGER_CONTROL_PATH) => {
+                self.control(request, body, CONNECTION_MANAGER, host).await
+            }
+            //We don't generate events.
+            ("SUBSCRIBE" | "UNSUBSCRIBE", _) => empty(501, "Not Implemented"),
+            _ => empty(404, "Not Found"),
+        }
+    }
+
+    async fn control(
+        &self,
+        request: &RequestHead,
+        body: &[u8],
+        service_type: &str,
+        host: &str,
+    ) -> (ResponseHead, String) {
+        let action = request
+            .headers
+            .get_str("SOAPACTION")
+            .and_then(SoapAction::parse)
+            .filter(|action| action.service_type == service_type);
+
+        let Some(action) = action else {
+            return fault(Fault::new(401, "Invalid Action"));
+        };
+
+        let arguments = match std::str::from_utf8(body)
+            .context("SOAP request isn't UTF-8.")
+            .and_then(soap::parse_arguments)
+        {
+            Ok(arguments) => arguments,
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Bad {} request: {:#}", action.action, e);
+                return fault(Fault::invalid_args());
+            }
+        };
+
+        debug!(target: "dlnaproxy", "{} {:?}", action.action, arguments);
+
+        let output = match (service_type, &self.content_directory) {
+            (CONTENT_DIRECTORY, content_directory) => {
+                content_directory
+                    .handle(action.action, &arguments, host)
+                    .await
+            }
+            (_, ContentDirectory::Forward(forwarder)) => {
+                forwarder
+                    .connection_manager(action.action, &arguments)
+                    .await
+            }
+            _ => connection_manager(action.action),
+        };
+
+        match output {
+            Ok(output) => {
+                let arguments: Vec<(&str, &str)> = output
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+
+                let response_action = format!("{}Response", action.action);
+
+                xml(soap::envelope(service_type, &response_action, &arguments))
+            }
+            Err(e) => fault(e),
+        }
+    }
+}
+
+/// Our own ConnectionManager, for aggregates: we only ever serve content, over HTTP.
+fn connection_manager(action: &str) -> Result<ActionOutput, Fault> {
+    match action {
+        "GetProtocolInfo" => Ok(vec![
+            ("Source", "http-get:*:*:*".into()),
+            ("Sink", String::new()),
+        ]),
+        "GetCurrentConnectionIDs" => Ok(vec![("ConnectionIDs", "0".into())]),
+        "GetCurrentConnectionInfo" => Ok(vec![
+            ("RcsID", "-1".into()),
+            ("AVTransportID", "-1".into()),
+            ("ProtocolInfo", String::new()),
+            ("PeerConnectionManager", String::new()),
+            ("PeerConnectionID", "-1".into()),
+            ("Direction", "Output".into()),
+            ("Status", "OK".into()),
+        ]),
+        _ => Err(Fault::new(401, "Invalid Action")),
+    }
+}
+
+fn xml(content: String) -> (ResponseHead, String) {
+    xml_with_status(200, "OK", content)
+}
+
+fn fault(fault: Fault) -> (ResponseHead, String) {
+    xml_with_status(500, "Internal Server Error", fault.to_envelope())
+}
+
+fn xml_with_status(status: u16, reason: &str, content: String) -> (ResponseHead, String) {
+    let mut response = ResponseHead::new(status, reason);
+    response
+        .headers
+        .set("Content-Type", "text/xml; charset=\"utf-8\"");
+
+    (response, content)
+}
+
+fn empty(status: u16, reason: &str) -> (ResponseHead, String) {
+    (ResponseHead::new(status, reason), String::new())
+}
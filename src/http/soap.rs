@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use anyhow::{anyhow, Context, Result};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesCData, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use reqwest::header::CONTENT_TYPE;
+use thiserror::Error;
+
+/// UPnP error reported by an action, sent back as a SOAP fault.
+#[derive(Debug, Error)]
+#[error("UPnP error {code}: {description}")]
+pub struct Fault {
+    pub code: u16,
+    pub description: String,
+}
+
+impl Fault {
+    pub fn new(code: u16, description: impl Into<String>) -> Self {
+        Fault {
+            code,
+            description: description.into(),
+        }
+    }
+
+    pub fn invalid_args() -> Self {
+        Fault::new(402, "Invalid Args")
+    }
+
+    pub fn action_failed() -> Self {
+        Fault::new(501, "Action Failed")
+    }
+
+    pub fn to_envelope(&self) -> String {
+        format!(
+            "{}<s:Fault>\
+<faultcode>s:Client</faultcode>\
+<faultstring>UPnPError</faultstring>\
+<detail>\
+<UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+<errorCode>{}</errorCode>\
+<errorDescription>{}</errorDescription>\
+</UPnPError>\
+</detail>\
+</s:Fault>{}",
+            ENVELOPE_START,
+            self.code,
+            escape(&self.description),
+            ENVELOPE_END
+        )
+    }
+}
+
+const ENVELOPE_START: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+<s:Body>";
+
+const ENVELOPE_END: &str = "</s:Body></s:Envelope>";
+
+/// Value of a SOAPACTION header: `"urn:schemas-upnp-org:service:ContentDirectory:1#Browse"`.
+pub struct SoapAction<'a> {
+    pub service_type: &'a str,
+    pub action: &'a str,
+}
+
+impl<'a> SoapAction<'a> {
+    pub fn parse(header: &'a str) -> Option<Self> {
+        let (service_type, action) = header.trim().trim_matches('"').split_once('#')?;
+
+        Some(SoapAction {
+            service_type,
+            action,
+        })
+    }
+
+    /// Whether the action belongs to a service of type `service` (e.g. `ContentDirectory`), any version.
+    pub fn is_service(&self, service: &str) -> bool {
+        self.service_type
+            .rsplit(':')
+            .nth(1)
+            .is_some_and(|name| name == service)
+    }
+}
+
+/// Copies a SOAP envelope, passing the (unescaped) text of every `argument` element through `rewrite`.
+pub fn rewrite_argument(
+    envelope: &str,
+    argument: &str,
+    rewrite: impl Fn(&str) -> String,
+) -> Result<String> {
+    let mut reader = Reader::from_str(envelope);
+    let mut writer = Writer::new(Cursor::new(Vec::with_capacity(envelope.len())));
+
+    let mut in_argument = false;
+
+    loop {
+        let event = reader
+            .read_event()
+            .context("Failed to parse SOAP envelope.")?;
+
+        match event {
+            Event::Eof => break,
+            Event::Start(ref start) => {
+                in_argument = start.local_name().as_ref() == argument.as_bytes();
+                writer.write_event(event)?;
+            }
+            Event::End(_) => {
+                in_argument = false;
+                writer.write_event(event)?;
+            }
+            Event::Text(ref text) if in_argument => {
+                let unescaped = text.unescape().context("Bad escaping in SOAP argument.")?;
+                let rewritten = rewrite(&unescaped);
+
+                writer.write_event(Event::Text(BytesText::new(&rewritten)))?;
+            }
+            Event::CData(ref cdata) if in_argument => {
+                let raw = std::str::from_utf8(cdata).context("SOAP argument isn't UTF-8.")?;
+                let rewritten = rewrite(raw);
+
+                writer.write_event(Event::CData(BytesCData::new(rewritten)))?;
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+
+    String::from_utf8(writer.into_inner().into_inner()).context("Rewritten envelope isn't UTF-8.")
+}
+
+/// Envelope carrying `action` (e.g. `Browse` or `BrowseResponse`) of `service_type` with its arguments, escaped here.
+pub fn envelope(service_type: &str, action: &str, arguments: &[(&str, &str)]) -> String {
+    let mut envelope = format!(
+        "{}<u:{} xmlns:u=\"{}\">",
+        ENVELOPE_START, action, service_type
+    );
+
+    for (name, value) in arguments {
+        envelope.push_str(&format!("<{0}>{1}</{0}>", name, escape(value)));
+    }
+
+    envelope.push_str(&format!("</u:{}>{}", action, ENVELOPE_END));
+    envelope
+}
+
+/// Arguments of the action (or action response) carried by a SOAP envelope, unescaped.
+pub fn parse_arguments(envelope: &str) -> Result<HashMap<String, String>> {
+    let mut reader = Reader::from_str(envelope);
+
+    let mut arguments = HashMap::new();
+    //Elements opened since Body: the action, then one of its arguments.
+    let mut depth_in_body: Option<usize> = None;
+    let mut current: Option<(String, String)> = None;
+
+    loop {
+        match reader
+            .read_event()
+            .context("Failed to parse SOAP envelope.")?
+        {
+            Event::Eof => break,
+            Event::Start(start) => match depth_in_body {
+                None if start.local_name().as_ref() == b"Body" => depth_in_body = Some(0),
+                None => {}
+                Some(depth) => {
+                    if depth == 1 {
+                        let name = String::from_utf8_lossy(start.local_name().as_ref()).into();
+                        current = Some((name, String::new()));
+                    }
+
+                    depth_in_body = Some(depth + 1);
+                }
+            },
+            Event::Empty(empty) if depth_in_body == Some(1) => {
+                let name = String::from_utf8_lossy(empty.local_name().as_ref()).into();
+                arguments.insert(name, String::new());
+            }
+            Event::End(_) => match depth_in_body {
+                Some(0) => break,
+                Some(depth) => {
+                    if depth == 2 {
+                        if let Some((name, value)) = current.take() {
+                            arguments.insert(name, value);
+                        }
+                    }
+
+                    depth_in_body = Some(depth - 1);
+                }
+                None => {}
+            },
+            Event::Text(text) => {
+                if let Some((_, value)) = current.as_mut() {
+                    value.push_str(&text.unescape().context("Bad escaping in SOAP argument.")?);
+                }
+            }
+            Event::CData(cdata) => {
+                if let Some((_, value)) = current.as_mut() {
+                    value.push_str(
+                        std::str::from_utf8(&cdata).context("SOAP argument isn't UTF-8.")?,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(arguments)
+}
+
+/// The UPnP error in a fault envelope.
+fn parse_fault(envelope: &str) -> Option<Fault> {
+    let mut reader = Reader::from_str(envelope);
+
+    let mut element = Vec::new();
+    let mut code = None;
+    let mut description = String::new();
+
+    loop {
+        match reader.read_event().ok()? {
+            Event::Eof => break,
+            Event::Start(start) => element = start.local_name().as_ref().to_vec(),
+            Event::End(_) => element.clear(),
+            Event::Text(text) => match element.as_slice() {
+                b"errorCode" => code = text.unescape().ok()?.trim().parse().ok(),
+                b"errorDescription" => description = text.unescape().ok()?.into_owned(),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    code.map(|code| Fault { code, description })
+}
+
+/// Invokes `action` on a remote service, returning its output arguments.
+/// UPnP errors come back as a [`Fault`].
+pub async fn call(
+    client: &reqwest::Client,
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+    arguments: &[(&str, &str)],
+) -> Result<HashMap<String, String>> {
+    let response = client
+        .post(control_url)
+        .header(CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+        .header("SOAPACTION", format!("\"{}#{}\"", service_type, action))
+        .body(envelope(service_type, action, arguments))
+        .send()
+        .await
+        .with_context(|| format!("Failed to call {} on {}.", action, control_url))?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Failed to read SOAP response.")?;
+
+    if !status.is_success() {
+        return Err(match parse_fault(&body) {
+            Some(fault) => fault.into(),
+            None => anyhow!("{} on {} failed with {}.", action, control_url, status),
+        });
+    }
+
+    parse_arguments(&body)
+}
@@ -1,44 +1,111 @@
 mod config;
+mod discovery;
+mod http;
+mod peer;
+mod relay;
+mod reverse;
 mod ssdp;
 mod tcp_proxy;
 
-use std::{net::SocketAddr, path::PathBuf, time};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time};
 
-use config::Config;
+use config::{Config, ProxyMode};
 
 use reqwest::Url;
 
 use anyhow::Result;
 use clap::{ArgAction, Parser};
-use log::{debug, trace};
+use log::{debug, info, trace, warn};
 use ssdp::main_task;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
-use crate::ssdp::SSDPManager;
+use crate::http::{
+    Aggregator, Backend, BrowseCache, ContentDirectory, DescriptionOverrides, Forwarder,
+    HttpContext, ImageCache, MediaServer,
+};
+use crate::peer::Peer;
+use crate::relay::Relay;
+use crate::reverse::Reverse;
+use crate::ssdp::search::SearchHosts;
+use crate::ssdp::{Endpoint, Follow, SSDPManager};
 use crate::tcp_proxy::TCPProxy;
 
+/// Connection timeout of our requests to remote servers.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
 /// Broadcast ssdp:alive messages on the local network's multicast SSDP channel on behalf of a remote DLNA server.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct CommandLineConf {
     /// TOML config file.
-    #[clap(short, long, value_name = "/path/to/config.conf", conflicts_with_all(&["description_url", "interval", "proxy"]))]
+    #[clap(short, long, value_name = "/path/to/config.conf", conflicts_with_all(&["description_url", "discover", "interval", "description_ttl", "proxy", "proxy_mode", "friendly_name", "model_name", "virtual_udn", "serve", "browse_cache", "follow", "image_cache", "image_cache_size", "ipv6"]))]
     config: Option<PathBuf>,
 
     /// URL pointing to the remote DLNA server's root XML description.
-    #[clap(short = 'u', long, value_name = "URL", required_unless_present("config"), value_parser = Url::parse)]
+    #[clap(short = 'u', long, value_name = "URL", required_unless_present_any(&["config", "discover"]), value_parser = Url::parse)]
     description_url: Option<Url>,
 
+    /// Host, or IPv4 subnet (e.g. 10.8.0.0/24), where to find the remote server with unicast M-SEARCHes, instead of giving its description URL.
+    #[clap(short = 'D', long, value_name = "HOST|SUBNET", conflicts_with = "description_url", value_parser = str::parse::<SearchHosts>)]
+    discover: Option<SearchHosts>,
+
     /// Interval at which we will check the remote server's presence and broadcast on its behalf, in seconds.
     #[clap(short = 'd', long, value_name = "DURATION")]
     interval: Option<u64>,
 
+    /// How long the remote server's description is cached before being fetched again, in seconds.
+    #[clap(short = 't', long, value_name = "DURATION")]
+    description_ttl: Option<u64>,
+
     /// IP address & port where to bind proxy.
     #[clap(short = 'p', long, value_name = "IP:PORT", value_parser)]
     proxy: Option<SocketAddr>,
 
-    /// Network interface on which to broadcast (requires root or CAP_NET_RAW capability).
-    #[clap(short, long, value_name = "IFACE")]
-    iface: Option<String>,
+    /// Whether the proxy relays raw TCP (default) or rewrites the remote server's URLs in descriptions and Browse results.
+    #[clap(short = 'm', long, value_enum, requires = "proxy")]
+    proxy_mode: Option<ProxyMode>,
+
+    /// Name announced in place of the remote server's, "{friendlyName}" standing for the original one (requires the HTTP proxy).
+    #[clap(long, value_name = "TEMPLATE", requires = "proxy")]
+    friendly_name: Option<String>,
+
+    /// Model name announced in place of the remote server's, "{modelName}" standing for the original one (requires the HTTP proxy).
+    #[clap(long, value_name = "TEMPLATE", requires = "proxy")]
+    model_name: Option<String>,
+
+    /// Announce the server under a stable UDN derived from its own, for it not to be mistaken for the remote server when both are visible (requires the HTTP proxy).
+    #[clap(long, requires = "proxy")]
+    virtual_udn: bool,
+
+    /// IP address & port where to serve a description of our own, whose ContentDirectory forwards to the remote server's.
+    #[clap(short = 's', long, value_name = "IP:PORT", value_parser)]
+    serve: Option<SocketAddr>,
+
+    /// Size of the HTTP proxy's Browse/Search response cache, in MiB (default: 16, 0 disables it).
+    #[clap(long, value_name = "MIB", requires = "proxy")]
+    browse_cache: Option<u64>,
+
+    /// Network interface on which the remote server's own announcements are heard (multicast being routed from its network), announcing it when it does instead of checking on it every interval.
+    #[clap(long, value_name = "IFACE")]
+    follow: Option<String>,
+
+    /// Directory where to keep the thumbnails and album art relayed by the HTTP proxy, across restarts.
+    #[clap(long, value_name = "DIR", requires = "proxy")]
+    image_cache: Option<PathBuf>,
+
+    /// Size of the image cache, in MiB (default: 64).
+    #[clap(long, value_name = "MIB", requires = "image_cache")]
+    image_cache_size: Option<u64>,
+
+    /// Network interface on which to broadcast (requires root or CAP_NET_RAW capability), can be repeated. "all" stands for every non-loopback interface.
+    #[clap(short, long, value_name = "IFACE", action = ArgAction::Append)]
+    iface: Vec<String>,
+
+    /// Also announce on the ff02::c and ff05::c IPv6 groups.
+    #[clap(long)]
+    ipv6: bool,
 
     /// Verbosity level. The more v, the more verbose.
     #[clap(short, long, action=ArgAction::Count)]
@@ -53,37 +120,329 @@ async fn main() -> Result<()> {
 
     init_logging(config.verbose);
 
-    let mut url = config.description_url;
+    let shutdown = CancellationToken::new();
 
-    let _tcp_proxy_thread = if let Some(proxy_addr) = config.proxy {
-        let server_addr = config::sockaddr_from_url(&url);
+    let _signal_handle = tokio::spawn(signal_handler(shutdown.clone()));
 
-        url.set_ip_host(proxy_addr.ip()).unwrap();
-        url.set_port(Some(proxy_addr.port())).unwrap();
+    let http_client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()?;
 
-        let proxy = TCPProxy;
+    //Shared by all proxies.
+    let image_cache = match &config.image_cache {
+        Some(cache) => Some(Arc::new(
+            ImageCache::open(&cache.dir, cache.max_size).await?,
+        )),
+        None => None,
+    };
 
-        trace!(target: "dlnaproxy", "server: {}", server_addr);
+    let setup = Arc::new(Setup {
+        config,
+        http_client,
+        image_cache,
+    });
+    let config = &setup.config;
 
-        Some(proxy.start(server_addr, proxy_addr))
-    } else {
-        None
+    //Aggregated servers make up a single device, which changes whenever one of them moves.
+    let units: Vec<Vec<usize>> = match config.aggregate {
+        Some(_) => vec![(0..config.servers.len()).collect()],
+        None => (0..config.servers.len()).map(|index| vec![index]).collect(),
     };
 
-    debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s, verbosity: {}", url, config.period.as_secs(), config.verbose);
+    let mut known: Option<(Vec<usize>, Vec<Url>)> = None;
+    let mut discovered = Vec::new();
+
+    for unit in units {
+        let urls: Option<Vec<Url>> = unit
+            .iter()
+            .map(|&index| config.servers[index].description.url().cloned())
+            .collect();
+
+        match urls {
+            Some(urls) => {
+                let (indices, known_urls) = known.get_or_insert_with(Default::default);
+
+                indices.extend(unit);
+                known_urls.extend(urls);
+            }
+            None => discovered.push(unit),
+        }
+    }
+
+    let mut tasks = Vec::new();
+
+    //Servers whose description URL is known are set up right away, all of them announced through the same sockets.
+    //Should their SSDP tasks stop on their own, everything else follows.
+    if let Some((indices, urls)) = known {
+        tasks.extend(setup.start(&indices, urls, shutdown.clone()).await?);
+    }
+
+    if let Some(reverse) = &config.reverse {
+        let reverse = Reverse::new(reverse.clone(), config.interfaces.clone(), config.ipv6)?;
+
+        tasks.push(tokio::spawn(reverse.run(shutdown.clone())));
+    }
+
+    if let Some(relay) = &config.relay {
+        tasks.push(tokio::spawn(
+            Relay::new(relay.clone())?.run(shutdown.clone()),
+        ));
+    }
+
+    if let Some(peer) = &config.peer {
+        let peer = Peer::new(peer.clone(), config.interfaces.clone(), config.ipv6)?;
+
+        tasks.push(tokio::spawn(peer.run(shutdown.clone())));
+    }
+
+    //The others are set up as they are discovered, each on its own, and set up again when they move.
+    for unit in discovered {
+        let servers = unit
+            .iter()
+            .map(|&index| config.servers[index].description.clone())
+            .collect();
+
+        let start = {
+            let setup = setup.clone();
+
+            move |urls, stop| {
+                let setup = setup.clone();
+                let unit = unit.clone();
+
+                async move { setup.start(&unit, urls, stop).await }
+            }
+        };
+
+        tasks.push(tokio::spawn(discovery::keep_up(
+            servers,
+            start,
+            shutdown.clone(),
+        )));
+    }
+
+    shutdown.cancelled().await;
+
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    info!(target: "dlnaproxy", "Exiting !");
+
+    Ok(())
+}
+
+/// What the servers' proxies and announcements are set up with.
+struct Setup {
+    config: Config,
+    http_client: reqwest::Client,
+    image_cache: Option<Arc<ImageCache>>,
+}
+
+impl Setup {
+    /// Proxies and announces the servers of the config at `indices`, described at `urls`, until `stop` is triggered.
+    async fn start(
+        &self,
+        indices: &[usize],
+        urls: Vec<Url>,
+        stop: CancellationToken,
+    ) -> Result<Vec<JoinHandle<()>>> {
+        let (config, http_client, image_cache) =
+            (&self.config, &self.http_client, &self.image_cache);
 
-    let timeout = time::Duration::from_secs(2);
-    let ssdp = SSDPManager::new(
-        url.as_str(),
-        config.period,
-        Some(timeout),
-        config.broadcast_iface,
-    )
-    .await?;
+        let mut endpoints = Vec::with_capacity(indices.len());
+        let mut tasks = Vec::new();
+        let mut backends = Vec::new();
+
+        for (server, mut url) in indices
+            .iter()
+            .map(|&index| &config.servers[index])
+            .zip(urls)
+        {
+            let overrides = DescriptionOverrides {
+                friendly_name: server.friendly_name.clone(),
+                model_name: server.model_name.clone(),
+                virtual_udn: server.virtual_udn,
+            };
+
+            //The description is only rewritten on its way through the HTTP proxy, or generated by our own server.
+            //We fetch it from there as well, which is how SSDP announcements pick up the virtual UDN.
+            let rewritten = server.serve.is_some()
+                || (server.proxy.is_some() && server.proxy_mode == ProxyMode::Http);
+
+            if !overrides.is_empty() && !rewritten {
+                warn!(target: "dlnaproxy", "Description overrides for '{}' require the HTTP proxy, ignoring them.", url);
+            }
+
+            if config.aggregate.is_some() && server.serve.is_some() {
+                warn!(target: "dlnaproxy", "'{}' is aggregated, not serving it on its own.", url);
+            }
+
+            if config.aggregate.is_some() && server.follow.is_some() {
+                warn!(target: "dlnaproxy", "'{}' is aggregated, not following its announcements.", url);
+            }
+
+            //Its announcements carry the remote URL, whatever we announce in its place.
+            let follow = server.follow.clone().map(|iface| Follow {
+                iface,
+                remote_url: url.clone(),
+            });
+
+            //Aggregated and served servers are reached through our own media server, the proxies only relay media.
+            let backend = || {
+                Backend::new(
+                    http_client.clone(),
+                    &url,
+                    config::sockaddr_from_url(&url),
+                    server.description_ttl,
+                    server.proxy,
+                )
+            };
+
+            let mut media_server = None;
+
+            if config.aggregate.is_some() {
+                backends.push(backend());
+            } else if let Some(addr) = server.serve {
+                let content_directory =
+                    ContentDirectory::Forward(Forwarder::new(http_client.clone(), backend()));
+
+                //Our UDN is already a virtual one.
+                let overrides = DescriptionOverrides {
+                    virtual_udn: false,
+                    ..overrides.clone()
+                };
+
+                let udn = http::virtual_udn(url.as_str());
+
+                media_server = Some((addr, MediaServer::new(&udn, overrides, content_directory)));
+            }
+
+            if let Some(proxy_addr) = server.proxy {
+                let server_addr = config::sockaddr_from_url(&url);
+
+                let proxy = match server.proxy_mode {
+                    ProxyMode::Tcp => TCPProxy::raw(),
+                    ProxyMode::Http => {
+                        let browse_cache = (server.browse_cache_size > 0).then(|| {
+                            BrowseCache::new(http_client.clone(), server.browse_cache_size)
+                        });
+
+                        TCPProxy::http(HttpContext::new(
+                            &url,
+                            server_addr,
+                            overrides,
+                            browse_cache,
+                            image_cache.clone(),
+                        ))
+                    }
+                };
+
+                url.set_ip_host(proxy_addr.ip()).unwrap();
+                url.set_port(Some(proxy_addr.port())).unwrap();
+
+                trace!(target: "dlnaproxy", "server: {}", server_addr);
+
+                tasks.push(proxy.start(server_addr, proxy_addr, stop.clone()).await?);
+            }
+
+            if config.aggregate.is_some() {
+                continue;
+            }
+
+            if let Some((addr, media_server)) = media_server {
+                tasks.push(media_server.start(addr, stop.clone()).await?);
+
+                url = Url::parse(&format!("http://{}{}", addr, http::DESCRIPTION_PATH))?;
+            }
+
+            debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s, verbosity: {}", url, server.period.as_secs(), config.verbose);
+
+            endpoints.push(Endpoint {
+                desc_url: url,
+                broadcast_period: server.period,
+                description_ttl: server.description_ttl,
+                follow,
+            });
+        }
+
+        if let Some(aggregate) = &config.aggregate {
+            //Stable as long as the same servers are aggregated.
+            let udn = http::virtual_udn(
+                &backends
+                    .iter()
+                    .map(|backend| backend.description_url().as_str())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+
+            let content_directory = ContentDirectory::Aggregate(Aggregator::new(
+                http_client.clone(),
+                &aggregate.friendly_name,
+                backends,
+            ));
+            let media_server =
+                MediaServer::new(&udn, DescriptionOverrides::default(), content_directory);
+
+            tasks.push(media_server.start(aggregate.bind, stop.clone()).await?);
+
+            let url = Url::parse(&format!(
+                "http://{}{}",
+                aggregate.bind,
+                http::DESCRIPTION_PATH
+            ))?;
+
+            debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s, verbosity: {}", url, aggregate.period.as_secs(), config.verbose);
+
+            //Our own description doesn't change, the periodic announcements are enough to check on it.
+            endpoints.push(Endpoint {
+                desc_url: url,
+                broadcast_period: aggregate.period,
+                description_ttl: aggregate.period,
+                follow: None,
+            });
+        }
+
+        if !endpoints.is_empty() {
+            let ssdp = SSDPManager::new(
+                endpoints,
+                Some(CONNECT_TIMEOUT),
+                config.interfaces.clone(),
+                config.ipv6,
+            )
+            .await?;
+
+            //Should the SSDP tasks stop on their own, the proxies follow.
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = main_task(ssdp, stop.clone()).await {
+                    warn!(target: "dlnaproxy", "SSDP tasks failed: {:#}", e);
+                }
+
+                stop.cancel();
+            }));
+        }
+
+        Ok(tasks)
+    }
+}
+
+/// Triggers an orderly shutdown on SIGINT, SIGTERM (systemd), SIGQUIT or SIGHUP.
+async fn signal_handler(shutdown: CancellationToken) -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    debug!(target:"dlnaproxy", "Signal handler waiting...");
+
+    let signal_name = tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigquit.recv() => "SIGQUIT",
+        _ = sighup.recv() => "SIGHUP",
+    };
 
-    let handle = tokio::spawn(main_task(ssdp));
+    info!(target:"dlnaproxy", "Received {}, shutting down.", signal_name);
 
-    let _ = handle.await;
+    shutdown.cancel();
 
     Ok(())
 }
@@ -0,0 +1,1161 @@
+use log::{debug, info, trace, warn};
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::Sha256;
+use tokio::io::{self, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, version::TLS13, ClientConfig, ServerConfig, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{PeerConfig, PeerLink};
+use crate::http;
+use crate::reverse::DeviceAnnouncer;
+use crate::ssdp::listener::parse_ssdp;
+use crate::ssdp::packet::SSDPPacket;
+use crate::ssdp::search::{self, Found};
+use crate::ssdp::{Interfaces, SSDP_ADDRESS};
+
+/// Delay before trying to reach the other instance again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Time the other instance has to prove it knows the secret.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Random bytes each side sends first, for the other one to prove it knows the secret with.
+const NONCE_LENGTH: usize = 32;
+
+/// Label of the TLS exporter whose value the handshake covers, tying the proofs to the link's TLS session.
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-dlnaproxy-peer";
+
+/// Name the listening side's certificate is made out to.
+const CERTIFICATE_NAME: &str = "dlnaproxy";
+
+/// Devices of the other side we announce at most, each one taking a proxy port.
+const MAX_REMOTE_DEVICES: usize = 64;
+
+/// Largest frame payload, connections being relayed in chunks of that size.
+const MAX_PAYLOAD: usize = 16 * 1024;
+
+/// Frames queued on the link before their senders wait.
+const QUEUE_LENGTH: usize = 64;
+
+/// Data frames a side may send on a stream before the other one says it wrote them to its connection, which is as
+/// many as it queues for it.
+const WINDOW: usize = 64;
+
+/// What a frame carries. Streams are connections to a device, opened by either side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FrameKind {
+    /// One of the sender's devices, as an ssdp:alive NOTIFY, the stream field being its id.
+    Announce = 0,
+    /// A connection to the device whose id is the payload.
+    Open = 1,
+    Data = 2,
+    /// The sender is done writing to the stream.
+    Close = 3,
+    /// The sender wrote as many of the stream's Data frames as the payload (a big-endian u32) says to its
+    /// connection, and makes room for as many more.
+    Window = 4,
+    /// The sender dropped the stream, its connection being gone: nothing more should be sent to it.
+    Reset = 5,
+}
+
+/// Kind, stream and payload length (both big-endian u32), then the payload.
+struct Frame {
+    kind: FrameKind,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: FrameKind, stream: u32, payload: Vec<u8>) -> Self {
+        Frame {
+            kind,
+            stream,
+            payload,
+        }
+    }
+
+    /// `None` once the other side closed the link.
+    async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        let kind = match reader.read_u8().await {
+            Ok(kind) => kind,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let kind = match kind {
+            0 => FrameKind::Announce,
+            1 => FrameKind::Open,
+            2 => FrameKind::Data,
+            3 => FrameKind::Close,
+            4 => FrameKind::Window,
+            5 => FrameKind::Reset,
+            kind => return Err(anyhow!("Unknown frame kind {}.", kind)),
+        };
+
+        let stream = reader.read_u32().await?;
+        let length = reader.read_u32().await? as usize;
+
+        if length > MAX_PAYLOAD {
+            return Err(anyhow!("Frame too large ({} bytes).", length));
+        }
+
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload).await?;
+
+        Ok(Some(Frame::new(kind, stream, payload)))
+    }
+
+    async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(9 + self.payload.len());
+
+        frame.push(self.kind as u8);
+        frame.extend_from_slice(&self.stream.to_be_bytes());
+        frame.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&self.payload);
+
+        writer.write_all(&frame).await
+    }
+}
+
+/// A stream, as the link's reader sees it.
+struct Stream {
+    /// Where the stream's data goes, to be written to its connection. `None` once the other side closed it.
+    incoming: Option<mpsc::Sender<Vec<u8>>>,
+    /// Data frames we may still send on the stream.
+    credit: Arc<Semaphore>,
+}
+
+/// Our end of a stream, relayed to and from its connection by `pipe`.
+struct StreamEnd {
+    id: u32,
+    generation: u64,
+    frames: mpsc::Sender<Frame>,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    credit: Arc<Semaphore>,
+}
+
+/// The connection with the other instance.
+struct Link {
+    /// Tells links apart, so that one being replaced doesn't meddle with its successor.
+    generation: u64,
+    frames: mpsc::Sender<Frame>,
+    streams: HashMap<u32, Stream>,
+}
+
+impl Link {
+    fn open(&mut self, id: u32) -> StreamEnd {
+        //The other side never has more than a window of Data frames in flight, so they always fit.
+        let (incoming_sender, incoming) = mpsc::channel(WINDOW);
+        let credit = Arc::new(Semaphore::new(WINDOW));
+
+        self.streams.insert(
+            id,
+            Stream {
+                incoming: Some(incoming_sender),
+                credit: credit.clone(),
+            },
+        );
+
+        StreamEnd {
+            id,
+            generation: self.generation,
+            frames: self.frames.clone(),
+            incoming,
+            credit,
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        //Wakes up the streams waiting to send.
+        for stream in self.streams.values() {
+            stream.credit.close();
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerState {
+    /// Our devices, their index being their id on the link.
+    local: Vec<Found>,
+    /// The other side's devices we announce, by UDN, along with their id there.
+    remote: HashMap<String, u32>,
+    link: Option<Link>,
+    generations: u64,
+    next_stream: u32,
+}
+
+/// Our side of the link's TLS sessions.
+enum LinkTls {
+    Accept(TlsAcceptor),
+    Connect(TlsConnector),
+}
+
+impl LinkTls {
+    /// The listening side's certificate is generated anew on every start, the connecting side doesn't check it:
+    /// the handshake proving both sides know the secret, over that very session, is what authenticates them.
+    fn new(link: &PeerLink) -> Result<Self> {
+        let provider = Arc::new(crypto::ring::default_provider());
+
+        match link {
+            PeerLink::Listen(_) => {
+                let certified = rcgen::generate_simple_self_signed(vec![CERTIFICATE_NAME.into()])
+                    .context("Failed to generate the link's certificate.")?;
+                let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+                let config = ServerConfig::builder_with_provider(provider)
+                    .with_protocol_versions(&[&TLS13])?
+                    .with_no_client_auth()
+                    .with_single_cert(vec![certified.cert.der().clone()], key.into())?;
+
+                Ok(LinkTls::Accept(TlsAcceptor::from(Arc::new(config))))
+            }
+            PeerLink::Connect(_) => {
+                let verifier = Arc::new(AnyCertificate(provider.clone()));
+
+                let config = ClientConfig::builder_with_provider(provider)
+                    .with_protocol_versions(&[&TLS13])?
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier)
+                    .with_no_client_auth();
+
+                Ok(LinkTls::Connect(TlsConnector::from(Arc::new(config))))
+            }
+        }
+    }
+
+    /// Sets up a TLS session over `stream`, along with the exporter value both sides share.
+    async fn handshake(&self, stream: TcpStream) -> Result<(TlsStream<TcpStream>, [u8; 32])> {
+        match self {
+            LinkTls::Accept(acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                let binding =
+                    stream
+                        .get_ref()
+                        .1
+                        .export_keying_material([0; 32], EXPORTER_LABEL, None)?;
+
+                Ok((stream.into(), binding))
+            }
+            LinkTls::Connect(connector) => {
+                let stream = connector
+                    .connect(ServerName::try_from(CERTIFICATE_NAME)?, stream)
+                    .await?;
+                let binding =
+                    stream
+                        .get_ref()
+                        .1
+                        .export_keying_material([0; 32], EXPORTER_LABEL, None)?;
+
+                Ok((stream.into(), binding))
+            }
+        }
+    }
+}
+
+/// Takes any certificate, as long as the peer holds its key.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Exchanges devices with another dlnaproxy instance over a single TLS connection, each side announcing the
+/// other's devices through proxies of their own. HTTP traffic to the devices goes through that connection too.
+pub struct Peer {
+    config: PeerConfig,
+    tls: LinkTls,
+    state: Mutex<PeerState>,
+    announcer: tokio::sync::Mutex<DeviceAnnouncer>,
+}
+
+impl Peer {
+    pub fn new(config: PeerConfig, interfaces: Interfaces, ipv6: bool) -> Result<Self> {
+        let announcer = DeviceAnnouncer::new(
+            config.proxy,
+            config.period,
+            config.description_ttl,
+            interfaces,
+            ipv6,
+            //The devices would send their events to our tunnel's loopback address, on their own host.
+            false,
+        );
+
+        //Even and odd stream ids, so that the two sides never open the same stream.
+        let next_stream = match config.link {
+            PeerLink::Listen(_) => 0,
+            PeerLink::Connect(_) => 1,
+        };
+
+        Ok(Peer {
+            tls: LinkTls::new(&config.link)?,
+            config,
+            state: Mutex::new(PeerState {
+                next_stream,
+                ..Default::default()
+            }),
+            announcer: tokio::sync::Mutex::new(announcer),
+        })
+    }
+
+    /// Searches for our devices and keeps the link up until `shutdown` is triggered.
+    pub async fn run(self, shutdown: CancellationToken) {
+        let peer = Arc::new(self);
+
+        let searching = tokio::spawn(peer.clone().search(shutdown.clone()));
+
+        match &peer.config.link {
+            PeerLink::Listen(addr) => {
+                if let Err(e) = peer.clone().listen(*addr, &shutdown).await {
+                    warn!(target: "dlnaproxy", "Peer link failed: {:#}", e);
+                }
+            }
+            PeerLink::Connect(addr) => peer.clone().connect(addr, &shutdown).await,
+        }
+
+        let _ = searching.await;
+
+        peer.announcer.lock().await.join().await;
+    }
+
+    async fn search(self: Arc<Self>, shutdown: CancellationToken) {
+        info!(target: "dlnaproxy", "Looking for '{}' on {:?} for the other side.", self.config.search_target, self.config.search_interfaces);
+
+        loop {
+            match search::search(&self.config.search_interfaces, &self.config.search_target).await {
+                Ok(found) => {
+                    let (frames, announcements) = {
+                        let mut state = self.state.lock().unwrap();
+                        let mut announcements = Vec::new();
+
+                        for device in found {
+                            //Known already, or one of the other side's devices, which we announce ourselves.
+                            if state.local.iter().any(|known| known.udn == device.udn)
+                                || state.remote.contains_key(&device.udn)
+                            {
+                                continue;
+                            }
+
+                            info!(target: "dlnaproxy", "Found {} at {}.", device.udn, device.location);
+
+                            announcements
+                                .push(self.announcement(state.local.len() as u32, &device));
+                            state.local.push(device);
+                        }
+
+                        (
+                            state.link.as_ref().map(|link| link.frames.clone()),
+                            announcements,
+                        )
+                    };
+
+                    //Without a link, they are announced once there is one.
+                    if let Some(frames) = frames {
+                        for announcement in announcements {
+                            let _ = frames.send(announcement).await;
+                        }
+                    }
+                }
+                Err(e) => warn!(target: "dlnaproxy", "Search failed: {:#}", e),
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(self.config.search_interval) => {}
+            }
+        }
+    }
+
+    async fn listen(self: Arc<Self>, addr: SocketAddr, shutdown: &CancellationToken) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Unable to bind peer address {}", addr))?;
+
+        info!(target: "dlnaproxy", "Waiting for the other instance on {}.", addr);
+
+        loop {
+            let (stream, peer_addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(target: "dlnaproxy", "Failed to accept peer connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            debug!(target: "dlnaproxy", "Connection from {}.", peer_addr);
+
+            //A new link replaces the current one, the other side having reconnected.
+            tokio::spawn(self.clone().serve_link(stream, shutdown.clone()));
+        }
+
+        Ok(())
+    }
+
+    async fn connect(self: Arc<Self>, addr: &str, shutdown: &CancellationToken) {
+        loop {
+            let connected = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                connected = TcpStream::connect(addr) => connected,
+            };
+
+            match connected {
+                Ok(stream) => self.clone().serve_link(stream, shutdown.clone()).await,
+                Err(e) => {
+                    warn!(target: "dlnaproxy", "Failed to reach the other instance at {}: {}", addr, e)
+                }
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            }
+        }
+    }
+
+    /// Sets up TLS over `stream`, then makes sure the other side knows the secret.
+    async fn secure(&self, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let (mut stream, binding) = self
+            .tls
+            .handshake(stream)
+            .await
+            .context("TLS handshake failed.")?;
+
+        authenticate(
+            &mut stream,
+            &self.config.secret,
+            &self.config.link,
+            &binding,
+        )
+        .await?;
+
+        Ok(stream)
+    }
+
+    async fn serve_link(self: Arc<Self>, stream: TcpStream, shutdown: CancellationToken) {
+        let peer_addr = stream
+            .peer_addr()
+            .map_or_else(|_| "the other instance".into(), |addr| addr.to_string());
+
+        let _ = stream.set_nodelay(true);
+
+        //Before anything else, a stranger mustn't take the place of the current link.
+        let secured = tokio::time::timeout(HANDSHAKE_TIMEOUT, self.secure(stream))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("No answer to the handshake.")));
+
+        let stream = match secured {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Refused the link with {}: {:#}", peer_addr, e);
+                return;
+            }
+        };
+
+        info!(target: "dlnaproxy", "Linked with {}.", peer_addr);
+
+        let (mut reader, mut writer) = io::split(stream);
+        let (frames, mut queued) = mpsc::channel::<Frame>(QUEUE_LENGTH);
+
+        let (generation, announcements) = {
+            let mut state = self.state.lock().unwrap();
+
+            state.generations += 1;
+            let generation = state.generations;
+
+            //The streams of the previous link, if any, go with it.
+            state.link = Some(Link {
+                generation,
+                frames: frames.clone(),
+                streams: HashMap::new(),
+            });
+
+            let announcements: Vec<_> = state
+                .local
+                .iter()
+                .enumerate()
+                .map(|(id, device)| self.announcement(id as u32, device))
+                .collect();
+
+            (generation, announcements)
+        };
+
+        let writing = async move {
+            while let Some(frame) = queued.recv().await {
+                frame.write_to(&mut writer).await?;
+
+                //TLS records are only sent once flushed, which can wait for the frames behind.
+                if queued.is_empty() {
+                    writer.flush().await?;
+                }
+            }
+
+            io::Result::Ok(())
+        };
+
+        let reading = async {
+            for announcement in announcements {
+                frames.send(announcement).await?;
+            }
+
+            while let Some(frame) = Frame::read_from(&mut reader).await? {
+                self.handle_frame(generation, frame, &shutdown).await?;
+            }
+
+            Ok(())
+        };
+
+        let result = tokio::select! {
+            _ = shutdown.cancelled() => Ok(()),
+            written = writing => written.context("Failed to write to the link."),
+            read = reading => read,
+        };
+
+        match result {
+            Ok(()) => debug!(target: "dlnaproxy", "Link with the other instance closed."),
+            Err(e) => warn!(target: "dlnaproxy", "Lost the link with the other instance: {:#}", e),
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if state
+            .link
+            .as_ref()
+            .is_some_and(|link| link.generation == generation)
+        {
+            state.link = None;
+        }
+    }
+
+    async fn handle_frame(
+        self: &Arc<Self>,
+        generation: u64,
+        frame: Frame,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        trace!(target: "dlnaproxy", "Received {:?} frame for stream {} ({} bytes).", frame.kind, frame.stream, frame.payload.len());
+
+        match frame.kind {
+            FrameKind::Announce => {
+                if let Err(e) = self
+                    .on_announce(frame.stream, &frame.payload, shutdown)
+                    .await
+                {
+                    warn!(target: "dlnaproxy", "Failed to announce a device of the other side: {:#}", e);
+                }
+            }
+            FrameKind::Open => self.on_open(generation, frame.stream, &frame.payload, shutdown)?,
+            FrameKind::Data => {
+                let incoming = self.with_link(generation, |link| {
+                    link.streams
+                        .get(&frame.stream)
+                        .and_then(|stream| stream.incoming.clone())
+                })?;
+
+                //Never waiting on a stream, whose connection may be slow, keeps the others going.
+                if let Some(incoming) = incoming {
+                    if let Err(mpsc::error::TrySendError::Full(_)) =
+                        incoming.try_send(frame.payload)
+                    {
+                        return Err(anyhow!("Stream {} overran its window.", frame.stream));
+                    }
+                }
+            }
+            FrameKind::Close => {
+                self.with_link(generation, |link| {
+                    if let Some(stream) = link.streams.get_mut(&frame.stream) {
+                        stream.incoming = None;
+                    }
+                })?;
+            }
+            FrameKind::Window => {
+                let granted =
+                    u32::from_be_bytes(
+                        frame.payload.as_slice().try_into().map_err(|_| {
+                            anyhow!("Bad Window frame for stream {}.", frame.stream)
+                        })?,
+                    ) as usize;
+
+                self.with_link(generation, |link| match link.streams.get(&frame.stream) {
+                    Some(stream) if stream.credit.available_permits() + granted > WINDOW => Err(
+                        anyhow!("Stream {} was granted more than its window.", frame.stream),
+                    ),
+                    Some(stream) => {
+                        stream.credit.add_permits(granted);
+                        Ok(())
+                    }
+                    None => Ok(()),
+                })??;
+            }
+            FrameKind::Reset => {
+                self.with_link(generation, |link| {
+                    if let Some(stream) = link.streams.remove(&frame.stream) {
+                        stream.credit.close();
+                    }
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Announces a device of the other side, unless we already do.
+    async fn on_announce(
+        self: &Arc<Self>,
+        id: u32,
+        payload: &[u8],
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let (_, headers) = parse_ssdp(payload)?;
+
+        let location = headers
+            .get("LOCATION")
+            .context("Announcement without LOCATION.")?
+            .trim();
+        let location =
+            Url::parse(location).with_context(|| format!("Bad LOCATION '{}'.", location))?;
+
+        let udn = headers
+            .get("USN")
+            .context("Announcement without USN.")?
+            .trim()
+            .split("::")
+            .next()
+            .unwrap_or_default()
+            .to_string();
+
+        {
+            let mut state = self.state.lock().unwrap();
+
+            //One of our devices, which the other side found through our own announcements.
+            if state.local.iter().any(|device| device.udn == udn) {
+                return Ok(());
+            }
+
+            //Its id changes if the other side restarted.
+            if let Some(known) = state.remote.get_mut(&udn) {
+                *known = id;
+                return Ok(());
+            }
+
+            if state.remote.len() >= MAX_REMOTE_DEVICES {
+                return Err(anyhow!(
+                    "Already announcing {} devices, ignoring {}.",
+                    MAX_REMOTE_DEVICES,
+                    udn
+                ));
+            }
+
+            state.remote.insert(udn.clone(), id);
+        }
+
+        info!(target: "dlnaproxy", "The other side has {} at {}.", udn, location);
+
+        //The device's proxy connects to this listener, whose connections are relayed over the link.
+        let tunnel = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let tunnel_addr = tunnel.local_addr()?;
+
+        let tunneling = tokio::spawn(self.clone().tunnel(tunnel, udn.clone(), shutdown.clone()));
+
+        let announced = self
+            .announcer
+            .lock()
+            .await
+            .announce(&udn, &location, tunnel_addr, shutdown)
+            .await;
+
+        if announced.is_err() {
+            //The next announcement will try again.
+            tunneling.abort();
+            self.state.lock().unwrap().remote.remove(&udn);
+        }
+
+        announced
+    }
+
+    /// Connects to one of our devices on behalf of the other side.
+    fn on_open(
+        self: &Arc<Self>,
+        generation: u64,
+        stream: u32,
+        payload: &[u8],
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let id = u32::from_be_bytes(
+            payload
+                .try_into()
+                .map_err(|_| anyhow!("Bad Open frame for stream {}.", stream))?,
+        );
+
+        let location = self
+            .state
+            .lock()
+            .unwrap()
+            .local
+            .get(id as usize)
+            .map(|device| device.location.clone());
+
+        let end = self.with_link(generation, |link| link.open(stream))?;
+
+        let peer = self.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let connected = match location {
+                Some(location) => connect_to(&location).await,
+                None => Err(anyhow!("Unknown device {}.", id)),
+            };
+
+            match connected {
+                Ok(connection) => peer.pipe(connection, end, shutdown).await,
+                Err(e) => {
+                    debug!(target: "dlnaproxy", "Failed to open stream {}: {:#}", stream, e);
+                    let _ = end
+                        .frames
+                        .send(Frame::new(FrameKind::Reset, stream, Vec::new()))
+                        .await;
+                    peer.forget_stream(&end);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Relays the connections to a device of the other side over the link.
+    async fn tunnel(
+        self: Arc<Self>,
+        listener: TcpListener,
+        udn: String,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            let (connection, _) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(target: "dlnaproxy", "Failed to accept tunnel connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            self.open_stream(connection, &udn, &shutdown);
+        }
+    }
+
+    /// Relays `connection` to the other side's device `udn`, as a new stream.
+    fn open_stream(
+        self: &Arc<Self>,
+        connection: TcpStream,
+        udn: &str,
+        shutdown: &CancellationToken,
+    ) {
+        let opened = {
+            let mut state = self.state.lock().unwrap();
+
+            let stream = state.next_stream;
+            state.next_stream = state.next_stream.wrapping_add(2);
+
+            let id = state.remote.get(udn).copied();
+
+            match (state.link.as_mut(), id) {
+                (Some(link), Some(id)) => Some((id, link.open(stream))),
+                _ => None,
+            }
+        };
+
+        //Dropping the connection is what the device being unreachable looks like to the proxy.
+        let Some((id, end)) = opened else {
+            debug!(target: "dlnaproxy", "No link with the other instance, dropping connection to {}.", udn);
+            return;
+        };
+
+        let peer = self.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let open = Frame::new(FrameKind::Open, end.id, id.to_be_bytes().to_vec());
+
+            if end.frames.send(open).await.is_ok() {
+                peer.pipe(connection, end, shutdown).await;
+            }
+        });
+    }
+
+    /// Relays `connection` as the stream `end`, until both sides are done writing or one of them is gone.
+    async fn pipe(
+        self: Arc<Self>,
+        connection: TcpStream,
+        mut end: StreamEnd,
+        shutdown: CancellationToken,
+    ) {
+        let (mut reader, mut writer) = connection.into_split();
+        let (stream, frames, credit) = (end.id, &end.frames, &end.credit);
+        let incoming = &mut end.incoming;
+
+        let to_connection = async {
+            let mut written = 0;
+
+            let relayed = async {
+                while let Some(data) = incoming.recv().await {
+                    writer.write_all(&data).await?;
+
+                    //Room is made by half windows, not to send a Window frame for every Data frame.
+                    written += 1;
+
+                    if written == WINDOW / 2 {
+                        let window = Frame::new(
+                            FrameKind::Window,
+                            stream,
+                            (written as u32).to_be_bytes().to_vec(),
+                        );
+                        let _ = frames.send(window).await;
+                        written = 0;
+                    }
+                }
+
+                writer.shutdown().await
+            }
+            .await;
+
+            if relayed.is_err() {
+                incoming.close();
+                let _ = frames
+                    .send(Frame::new(FrameKind::Reset, stream, Vec::new()))
+                    .await;
+            }
+
+            relayed
+        };
+
+        let to_link = async {
+            let mut buffer = vec![0; MAX_PAYLOAD];
+
+            let relayed = async {
+                loop {
+                    //The other side reset the stream, or the link is gone.
+                    let Ok(permit) = credit.acquire().await else {
+                        return io::Result::Ok(());
+                    };
+                    permit.forget();
+
+                    let read = reader.read(&mut buffer).await?;
+
+                    if read == 0 {
+                        return Ok(());
+                    }
+
+                    let data = Frame::new(FrameKind::Data, stream, buffer[..read].to_vec());
+
+                    //The link is gone.
+                    if frames.send(data).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            .await;
+
+            let _ = frames
+                .send(Frame::new(FrameKind::Close, stream, Vec::new()))
+                .await;
+
+            relayed
+        };
+
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            (written, read) = async { tokio::join!(to_connection, to_link) } => {
+                if let Err(e) = written.and(read) {
+                    debug!(target: "dlnaproxy", "Stream {} ended: {}", stream, e);
+                }
+            }
+        }
+
+        self.forget_stream(&end);
+    }
+
+    fn forget_stream(&self, end: &StreamEnd) {
+        let _ = self.with_link(end.generation, |link| link.streams.remove(&end.id));
+    }
+
+    /// Runs `f` on the link, provided it wasn't replaced since.
+    fn with_link<T>(&self, generation: u64, f: impl FnOnce(&mut Link) -> T) -> Result<T> {
+        match &mut self.state.lock().unwrap().link {
+            Some(link) if link.generation == generation => Ok(f(link)),
+            _ => Err(anyhow!("Replaced by a new link.")),
+        }
+    }
+
+    fn announcement(&self, id: u32, device: &Found) -> Frame {
+        let notify = SSDPPacket::Alive {
+            host: SSDP_ADDRESS.into(),
+            desc_url: device.location.to_string(),
+            server_ua: http::server_header(),
+            notification_type: "upnp:rootdevice".into(),
+            unique_service_name: format!("{}::upnp:rootdevice", device.udn),
+            cache_max_age: self.config.search_interval.as_secs() as usize * 2,
+        };
+
+        Frame::new(FrameKind::Announce, id, notify.to_string().into_bytes())
+    }
+}
+
+/// Proves to the other instance that we know the secret, and checks that it does too, without either revealing it:
+/// each side sends a nonce, then a MAC of both nonces keyed with the secret. The MAC covers the sender's role, so that
+/// one side's proof can't be sent back to it, and `binding`, which is only the same on both sides of a single TLS
+/// session: a proof relayed by someone in the middle is worthless.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    secret: &str,
+    link: &PeerLink,
+    binding: &[u8],
+) -> Result<()> {
+    let (role, their_role): (&[u8], &[u8]) = match link {
+        PeerLink::Listen(_) => (b"listen", b"connect"),
+        PeerLink::Connect(_) => (b"connect", b"listen"),
+    };
+
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    stream.write_all(&nonce).await?;
+    stream.flush().await?;
+
+    let mut their_nonce = [0; NONCE_LENGTH];
+    stream.read_exact(&mut their_nonce).await?;
+
+    let proof = handshake_mac(secret, role, binding, &their_nonce, &nonce).finalize();
+    stream.write_all(&proof.into_bytes()).await?;
+    stream.flush().await?;
+
+    let mut their_proof = [0; 32];
+    stream.read_exact(&mut their_proof).await?;
+
+    handshake_mac(secret, their_role, binding, &nonce, &their_nonce)
+        .verify_slice(&their_proof)
+        .map_err(|_| anyhow!("The other instance doesn't know our secret."))
+}
+
+fn handshake_mac(
+    secret: &str,
+    role: &[u8],
+    binding: &[u8],
+    challenge: &[u8],
+    nonce: &[u8],
+) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC keys may be of any length");
+
+    mac.update(role);
+    mac.update(binding);
+    mac.update(challenge);
+    mac.update(nonce);
+
+    mac
+}
+
+async fn connect_to(location: &Url) -> Result<TcpStream> {
+    let addr = *location
+        .socket_addrs(|| None)
+        .context("Unreachable LOCATION.")?
+        .first()
+        .context("Unreachable LOCATION.")?;
+
+    TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to {}", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(link: PeerLink) -> Arc<Peer> {
+        let config = PeerConfig {
+            link,
+            secret: "secret".into(),
+            search_interfaces: Interfaces::Default,
+            search_target: "upnp:rootdevice".into(),
+            search_interval: Duration::from_secs(60),
+            proxy: (Ipv4Addr::LOCALHOST, 0).into(),
+            period: Duration::from_secs(60),
+            description_ttl: Duration::from_secs(60),
+        };
+
+        Arc::new(Peer::new(config, Interfaces::Default, false).unwrap())
+    }
+
+    /// A connection to `listener` and the accepted end of it.
+    async fn connection(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_does_not_hold_up_the_link() {
+        //More than socket buffers hold, for the stalled stream to fill up its queue.
+        const SENT: usize = 64 * 1024 * 1024;
+
+        let shutdown = CancellationToken::new();
+
+        //A device sending a lot of data on every connection.
+        let device = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let location = Url::parse(&format!("http://{}/", device.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut connection, _) = device.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let _ = connection.write_all(&vec![0; SENT]).await;
+                });
+            }
+        });
+
+        let listening = peer(PeerLink::Listen((Ipv4Addr::LOCALHOST, 0).into()));
+        let connecting = peer(PeerLink::Connect(String::new()));
+
+        listening.state.lock().unwrap().local.push(Found {
+            udn: "uuid:device".into(),
+            location,
+        });
+        connecting
+            .state
+            .lock()
+            .unwrap()
+            .remote
+            .insert("uuid:device".into(), 0);
+
+        let link = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (client, server) = connection(&link).await;
+
+        tokio::spawn(listening.clone().serve_link(server, shutdown.clone()));
+        tokio::spawn(connecting.clone().serve_link(client, shutdown.clone()));
+
+        while connecting.state.lock().unwrap().link.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let proxy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        //Never read from.
+        let (_stalled, server) = connection(&proxy).await;
+        connecting.open_stream(server, "uuid:device", &shutdown);
+
+        let (mut reading, server) = connection(&proxy).await;
+        connecting.open_stream(server, "uuid:device", &shutdown);
+
+        let mut received = Vec::new();
+        let read =
+            tokio::time::timeout(Duration::from_secs(10), reading.read_to_end(&mut received)).await;
+
+        shutdown.cancel();
+
+        assert!(
+            read.is_ok(),
+            "The second stream stalled along the first one."
+        );
+        assert_eq!(received.len(), SENT);
+    }
+
+    #[tokio::test]
+    async fn handshake_needs_the_same_secret() {
+        let listening = PeerLink::Listen((Ipv4Addr::LOCALHOST, 0).into());
+        let connecting = PeerLink::Connect(String::new());
+
+        let (mut a, mut b) = io::duplex(1024);
+        let (a, b) = tokio::join!(
+            authenticate(&mut a, "secret", &listening, b"session"),
+            authenticate(&mut b, "secret", &connecting, b"session")
+        );
+        assert!(a.is_ok() && b.is_ok());
+
+        let (mut a, mut b) = io::duplex(1024);
+        let (a, b) = tokio::join!(
+            authenticate(&mut a, "secret", &listening, b"session"),
+            authenticate(&mut b, "guess", &connecting, b"session")
+        );
+        assert!(a.is_err() && b.is_err());
+
+        //Its own proof sent back to it.
+        let (mut a, mut b) = io::duplex(1024);
+        let (a, b) = tokio::join!(
+            authenticate(&mut a, "secret", &listening, b"session"),
+            authenticate(&mut b, "secret", &listening, b"session")
+        );
+        assert!(a.is_err() && b.is_err());
+
+        //Proofs relayed between two TLS sessions by someone in the middle.
+        let (mut a, mut b) = io::duplex(1024);
+        let (a, b) = tokio::join!(
+            authenticate(&mut a, "secret", &listening, b"session"),
+            authenticate(&mut b, "secret", &connecting, b"another session")
+        );
+        assert!(a.is_err() && b.is_err());
+    }
+}
@@ -0,0 +1,641 @@
+use log::{debug, info, trace, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use httparse::{Response, EMPTY_HEADER};
+use reqwest::Url;
+use tokio::net::lookup_host;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::RelayConfig;
+use crate::http::{DescriptionOverrides, HttpContext};
+use crate::ssdp::listener::{parse_ssdp, MAX_MX, MIN_MX};
+use crate::ssdp::{bind_ssdp_socket, ssdp_socket_v4, SSDPSocket, SSDP_ADDRESS};
+use crate::tcp_proxy::TCPProxy;
+
+/// Added to the packets we relay, so that we (or another relay) don't send them back where they came from.
+const RELAYED_HEADER: &str = "X-DLNAPROXY-RELAYED";
+
+/// One of the two interfaces between which we relay.
+struct Side {
+    name: String,
+    ssdp: SSDPSocket,
+}
+
+/// Most searches relayed at once, each holding a socket for up to MX seconds.
+const MAX_SEARCHES: usize = 16;
+
+/// Most devices proxied at once, whatever hosts announce on either side.
+const MAX_PROXIES: usize = 64;
+
+/// How often proxies of devices whose announcements expired are stopped.
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// UDA's minimum CACHE-CONTROL, for packets without a usable one.
+const DEFAULT_MAX_AGE: u64 = 1800;
+
+/// Longest a device is proxied without hearing from it, whatever max-age it announces.
+const MAX_MAX_AGE: u64 = 24 * 3600;
+
+/// The proxy of a device, until its announcements expire or it says goodbye.
+struct DeviceProxy {
+    location: String,
+    addr: SocketAddr,
+    expires: time::Instant,
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Proxies {
+    /// By UDN.
+    by_udn: HashMap<String, DeviceProxy>,
+    /// Ports of stopped proxies, used again before new ones.
+    free_ports: Vec<u16>,
+    next_port: u16,
+}
+
+impl Proxies {
+    fn remove(&mut self, udn: &str) -> Option<DeviceProxy> {
+        let proxy = self.by_udn.remove(udn)?;
+
+        proxy.stop.cancel();
+        self.free_ports.push(proxy.addr.port());
+
+        Some(proxy)
+    }
+
+    fn remove_expired(&mut self) {
+        let now = time::Instant::now();
+
+        let expired: Vec<String> = self
+            .by_udn
+            .iter()
+            .filter(|(_, proxy)| proxy.expires <= now)
+            .map(|(udn, _)| udn.clone())
+            .collect();
+
+        for udn in expired {
+            if let Some(proxy) = self.remove(&udn) {
+                info!(target: "dlnaproxy", "{} expired, no longer relaying {}.", udn, proxy.location);
+            }
+        }
+    }
+
+    fn port(&mut self) -> Result<u16> {
+        if let Some(port) = self.free_ports.pop() {
+            return Ok(port);
+        }
+
+        let port = self.next_port;
+
+        self.next_port = self
+            .next_port
+            .checked_add(1)
+            .context("Ran out of proxy ports.")?;
+
+        Ok(port)
+    }
+}
+
+/// Relays NOTIFY and M-SEARCH (along with the responses) between two interfaces, over IPv4.
+/// With a proxy, each device found gets one of its own, on consecutive ports, and LOCATIONs point to it.
+pub struct Relay {
+    config: RelayConfig,
+    sides: [Side; 2],
+    /// Devices of the relayed types, whose root device and service packets are relayed too.
+    udns: Mutex<HashSet<String>>,
+    /// Searches being relayed, by searcher and target, the same one being relayed once.
+    searches: Mutex<HashSet<(SocketAddr, String)>>,
+    proxies: tokio::sync::Mutex<Proxies>,
+}
+
+impl Relay {
+    pub fn new(config: RelayConfig) -> Result<Self> {
+        let sides = config
+            .interfaces
+            .resolve()?
+            .context("The relay needs two interfaces.")?
+            .into_iter()
+            .map(|iface| {
+                if iface.addrs.v4.is_none() {
+                    return Err(anyhow!("{} has no IPv4 address to relay on.", iface.name));
+                }
+
+                Ok(Side {
+                    ssdp: ssdp_socket_v4(Some(&iface))?,
+                    name: iface.name,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let sides: [Side; 2] = sides
+            .try_into()
+            .map_err(|_| anyhow!("The relay needs two interfaces."))?;
+
+        let proxies = Proxies {
+            next_port: config.proxy.map_or(0, |proxy| proxy.port()),
+            ..Default::default()
+        };
+
+        Ok(Relay {
+            config,
+            sides,
+            udns: Mutex::new(HashSet::new()),
+            searches: Mutex::new(HashSet::new()),
+            proxies: tokio::sync::Mutex::new(proxies),
+        })
+    }
+
+    /// Relays until `shutdown` is triggered.
+    pub async fn run(self, shutdown: CancellationToken) {
+        info!(target: "dlnaproxy", "Relaying SSDP between {} and {}.", self.sides[0].name, self.sides[1].name);
+
+        let relay = Arc::new(self);
+
+        let listeners: Vec<_> = (0..relay.sides.len())
+            .map(|side| tokio::spawn(relay.clone().listen(side, shutdown.clone())))
+            .collect();
+
+        if relay.config.proxy.is_some() {
+            relay.reap(&shutdown).await;
+        }
+
+        for listener in listeners {
+            let _ = listener.await;
+        }
+
+        let proxies = std::mem::take(&mut relay.proxies.lock().await.by_udn);
+
+        for (_, proxy) in proxies {
+            let _ = proxy.task.await;
+        }
+
+        debug!(target: "dlnaproxy", "Stopped relaying SSDP.");
+    }
+
+    /// Stops the proxies of devices we stopped hearing from, until `shutdown` is triggered.
+    async fn reap(&self, shutdown: &CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = time::sleep(REAP_INTERVAL) => {}
+            }
+
+            self.proxies.lock().await.remove_expired();
+        }
+    }
+
+    async fn listen(self: Arc<Self>, from: usize, shutdown: CancellationToken) {
+        let mut buffer = [0; 2048];
+
+        loop {
+            let (length, sender) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = self.sides[from].ssdp.socket.recv_from(&mut buffer) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!(target: "dlnaproxy", "Failed to receive SSDP packet on {}: {}", self.sides[from].name, e);
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(e) = self
+                .handle(from, &buffer[..length], sender, &shutdown)
+                .await
+            {
+                debug!(target: "dlnaproxy", "Not relaying packet from {}: {:#}", sender, e);
+            }
+        }
+    }
+
+    async fn handle(
+        self: &Arc<Self>,
+        from: usize,
+        packet: &[u8],
+        sender: SocketAddr,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let (method, headers) = parse_ssdp(packet)?;
+
+        if headers.contains_key(RELAYED_HEADER) {
+            trace!(target: "dlnaproxy", "Ignoring packet relayed by {}.", sender);
+            return Ok(());
+        }
+
+        let header = |name: &str| headers.get(name).map_or("", |value| value.trim());
+
+        match method.as_str() {
+            "NOTIFY" => {
+                if !self.wanted(header("NT"), header("USN")) {
+                    return Ok(());
+                }
+
+                let to = &self.sides[1 - from];
+                let group = to.ssdp.groups[0];
+
+                //Its proxy goes, once the other side was told.
+                let bye = header("NTS") == "ssdp:byebye";
+
+                let location = match headers.get("LOCATION") {
+                    Some(location) if !bye => {
+                        self.location_for(
+                            header("USN"),
+                            location.trim(),
+                            header("CACHE-CONTROL"),
+                            &to.ssdp,
+                            group,
+                            shutdown,
+                        )
+                        .await?
+                    }
+                    _ => None,
+                };
+
+                let relayed = relayed(packet, location.as_deref())?;
+
+                to.ssdp
+                    .socket
+                    .send_to(relayed.as_bytes(), group)
+                    .await
+                    .context("Failed to relay NOTIFY.")?;
+
+                trace!(target: "dlnaproxy", "Relayed {} for {} from {} to {}.", header("NTS"), header("USN"), self.sides[from].name, to.name);
+
+                if bye {
+                    let udn = header("USN").split("::").next().unwrap_or_default();
+
+                    if let Some(proxy) = self.proxies.lock().await.remove(udn) {
+                        info!(target: "dlnaproxy", "{} left, no longer relaying {}.", udn, proxy.location);
+                    }
+                }
+            }
+            "M-SEARCH" => {
+                //Searches are relayed as they are, the responses are filtered.
+                let max_wait = headers
+                    .get("MX")
+                    .and_then(|mx| mx.trim().parse::<u64>().ok())
+                    .unwrap_or(MIN_MX)
+                    .clamp(MIN_MX, MAX_MX);
+
+                let search = (sender, header("ST").to_string());
+
+                {
+                    let mut searches = self.searches.lock().unwrap();
+
+                    //Control points repeat their searches, the responses to the first one will do.
+                    if searches.contains(&search) {
+                        trace!(target: "dlnaproxy", "Already relaying M-SEARCH for '{}' from {}.", search.1, sender);
+                        return Ok(());
+                    }
+
+                    if searches.len() >= MAX_SEARCHES {
+                        return Err(anyhow!("Already relaying {} searches.", MAX_SEARCHES));
+                    }
+
+                    searches.insert(search.clone());
+                }
+
+                debug!(target: "dlnaproxy", "Relaying M-SEARCH for '{}' from {} to {}.", search.1, sender, self.sides[1 - from].name);
+
+                let relay = self.clone();
+                let packet = packet.to_vec();
+                let shutdown = shutdown.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = relay
+                        .relay_search(from, &packet, max_wait, sender, &shutdown)
+                        .await
+                    {
+                        warn!(target: "dlnaproxy", "Failed to relay M-SEARCH from {}: {:#}", sender, e);
+                    }
+
+                    relay.searches.lock().unwrap().remove(&search);
+                });
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Searches on the other side on behalf of `searcher`, relaying the responses to it as they come.
+    async fn relay_search(
+        &self,
+        from: usize,
+        packet: &[u8],
+        max_wait: u64,
+        searcher: SocketAddr,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let to = &self.sides[1 - from];
+
+        //A socket of its own, so that the responses are told apart from other searches'.
+        let socket = bind_ssdp_socket((Ipv4Addr::UNSPECIFIED, 0).into(), Some(&to.name))?;
+
+        socket
+            .send_to(relayed(packet, None)?.as_bytes(), SSDP_ADDRESS)
+            .await
+            .context("Failed to relay M-SEARCH.")?;
+
+        let deadline = time::Instant::now() + Duration::from_secs(max_wait + 1);
+        let mut buffer = [0; 2048];
+
+        loop {
+            let (length, responder) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                received = time::timeout_at(deadline, socket.recv_from(&mut buffer)) => match received {
+                    Ok(received) => received.context("Failed to receive search responses.")?,
+                    Err(_) => break,
+                },
+            };
+
+            if let Err(e) = self
+                .relay_response(from, &buffer[..length], searcher, shutdown)
+                .await
+            {
+                debug!(target: "dlnaproxy", "Not relaying search response from {}: {:#}", responder, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn relay_response(
+        &self,
+        to: usize,
+        packet: &[u8],
+        searcher: SocketAddr,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let headers = parse_response(packet)?;
+
+        if headers.contains_key(RELAYED_HEADER) {
+            return Ok(());
+        }
+
+        let header = |name: &str| headers.get(name).map_or("", String::as_str);
+
+        if !self.wanted(header("ST"), header("USN")) {
+            return Ok(());
+        }
+
+        let to = &self.sides[to];
+
+        let location = match headers.get("LOCATION") {
+            Some(location) => {
+                self.location_for(
+                    header("USN"),
+                    location,
+                    header("CACHE-CONTROL"),
+                    &to.ssdp,
+                    searcher,
+                    shutdown,
+                )
+                .await?
+            }
+            None => None,
+        };
+
+        to.ssdp
+            .socket
+            .send_to(relayed(packet, location.as_deref())?.as_bytes(), searcher)
+            .await
+            .context("Failed to relay search response.")?;
+
+        trace!(target: "dlnaproxy", "Relayed search response for {} to {}.", header("USN"), searcher);
+
+        Ok(())
+    }
+
+    /// Whether a packet about `target` (NT or ST) from `usn` is to be relayed.
+    fn wanted(&self, target: &str, usn: &str) -> bool {
+        if self.config.types.is_empty() {
+            return true;
+        }
+
+        let udn = usn.split("::").next().unwrap_or_default();
+        let mut udns = self.udns.lock().unwrap();
+
+        if self
+            .config
+            .types
+            .iter()
+            .any(|wanted| target.starts_with(wanted.as_str()))
+        {
+            udns.insert(udn.to_string());
+            return true;
+        }
+
+        //Root device, UDN and service packets only tell which device they are about through the USN.
+        //Those sent before the device type's are dropped, the next round of announcements will do.
+        udns.contains(udn)
+    }
+
+    /// LOCATION to advertise to `dest` through `ssdp`: that of the proxy of the device `usn` is about, started on first use
+    /// and kept for as long as `cache_control` says. `None` when there is no proxy, the LOCATION being relayed as is.
+    async fn location_for(
+        &self,
+        usn: &str,
+        location: &str,
+        cache_control: &str,
+        ssdp: &SSDPSocket,
+        dest: SocketAddr,
+        shutdown: &CancellationToken,
+    ) -> Result<Option<String>> {
+        let Some(proxy) = self.config.proxy else {
+            return Ok(None);
+        };
+
+        let udn = usn.split("::").next().unwrap_or_default();
+
+        if udn.is_empty() {
+            return Err(anyhow!("No USN to tell the device by."));
+        }
+
+        let mut url =
+            Url::parse(location).with_context(|| format!("Bad LOCATION '{}'.", location))?;
+
+        let expires = time::Instant::now() + Duration::from_secs(max_age(cache_control));
+
+        let known = {
+            let mut proxies = self.proxies.lock().await;
+
+            match proxies.by_udn.get_mut(udn) {
+                Some(known) if known.location == location => {
+                    known.expires = known.expires.max(expires);
+                    Some(known.addr)
+                }
+                _ => None,
+            }
+        };
+
+        let proxy_addr = match known {
+            Some(proxy_addr) => proxy_addr,
+            None => {
+                let host = url.host_str().context("LOCATION without a host.")?;
+                let port = url
+                    .port_or_known_default()
+                    .context("LOCATION without a port.")?;
+
+                let device_addr = lookup_host((host, port))
+                    .await?
+                    .next()
+                    .context("Unreachable LOCATION.")?;
+
+                self.start_proxy(udn, location, device_addr, proxy, expires, shutdown)
+                    .await?
+            }
+        };
+        let host = match proxy_addr.ip() {
+            ip if ip.is_unspecified() => ssdp
+                .location_host(ip, dest)
+                .context("No address to advertise the proxy at.")?,
+            ip => ip,
+        };
+
+        url.set_ip_host(host).unwrap();
+        url.set_port(Some(proxy_addr.port())).unwrap();
+
+        Ok(Some(url.to_string()))
+    }
+
+    /// Starts the proxy of `udn`, replacing the one of its previous LOCATION.
+    async fn start_proxy(
+        &self,
+        udn: &str,
+        location: &str,
+        device_addr: SocketAddr,
+        proxy: SocketAddr,
+        expires: time::Instant,
+        shutdown: &CancellationToken,
+    ) -> Result<SocketAddr> {
+        let mut proxies = self.proxies.lock().await;
+
+        //Started in the meantime, by another packet about the same device.
+        if let Some(known) = proxies.by_udn.get(udn) {
+            if known.location == location {
+                return Ok(known.addr);
+            }
+        }
+
+        proxies.remove(udn);
+
+        if proxies.by_udn.len() >= MAX_PROXIES {
+            proxies.remove_expired();
+        }
+
+        if proxies.by_udn.len() >= MAX_PROXIES {
+            return Err(anyhow!("Already relaying {} devices.", MAX_PROXIES));
+        }
+
+        let proxy_addr = SocketAddr::new(proxy.ip(), proxies.port()?);
+
+        //The device keeps its own UDN: it is the same device, seen from another network.
+        let context = HttpContext::new(
+            &Url::parse(location)?,
+            device_addr,
+            DescriptionOverrides::default(),
+            None,
+            None,
+        );
+
+        let stop = shutdown.child_token();
+
+        let task = match TCPProxy::http(context)
+            .start(device_addr, proxy_addr, stop.clone())
+            .await
+        {
+            Ok(task) => task,
+            Err(e) => {
+                proxies.free_ports.push(proxy_addr.port());
+                return Err(e);
+            }
+        };
+
+        proxies.by_udn.insert(
+            udn.into(),
+            DeviceProxy {
+                location: location.into(),
+                addr: proxy_addr,
+                expires,
+                stop,
+                task,
+            },
+        );
+
+        info!(target: "dlnaproxy", "Relaying {} through {}.", location, proxy_addr);
+
+        Ok(proxy_addr)
+    }
+}
+
+/// Seconds a packet's `cache_control` (CACHE-CONTROL) says it holds for.
+fn max_age(cache_control: &str) -> u64 {
+    cache_control
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age"))
+        .and_then(|value| value.trim().strip_prefix('='))
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(DEFAULT_MAX_AGE)
+        .min(MAX_MAX_AGE)
+}
+
+/// `packet` with our marker header, and LOCATION replaced if given.
+fn relayed(packet: &[u8], location: Option<&str>) -> Result<String> {
+    let packet = std::str::from_utf8(packet).context("SSDP packet isn't UTF-8.")?;
+
+    //SSDP packets have no body.
+    let head = packet.split("\r\n\r\n").next().unwrap_or(packet);
+
+    let mut relayed = String::with_capacity(packet.len() + 64);
+
+    for line in head.split("\r\n") {
+        match (location, line.split_once(':')) {
+            (Some(location), Some((name, _))) if name.trim().eq_ignore_ascii_case("LOCATION") => {
+                relayed.push_str("LOCATION:");
+                relayed.push_str(location);
+            }
+            _ => relayed.push_str(line),
+        }
+
+        relayed.push_str("\r\n");
+    }
+
+    relayed.push_str(RELAYED_HEADER);
+    relayed.push_str(":1\r\n\r\n");
+
+    Ok(relayed)
+}
+
+/// Headers of a search response, by upper-case name.
+fn parse_response(packet: &[u8]) -> Result<HashMap<String, String>> {
+    let mut headers = [EMPTY_HEADER; 16];
+    let mut response = Response::new(&mut headers);
+
+    response
+        .parse(packet)
+        .context("Failed to parse search response.")?;
+
+    if response.code != Some(200) {
+        return Err(anyhow!("Unexpected status {:?}.", response.code));
+    }
+
+    Ok(response
+        .headers
+        .iter()
+        .map(|header| {
+            (
+                header.name.to_uppercase(),
+                String::from_utf8_lossy(header.value).trim().to_string(),
+            )
+        })
+        .collect())
+}
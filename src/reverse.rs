@@ -0,0 +1,271 @@
+use log::{debug, info, warn};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use tokio::net::lookup_host;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ReverseConfig;
+use crate::http::{DescriptionOverrides, HttpContext};
+use crate::ssdp::{main_task, search, Endpoint, Interfaces, SSDPManager};
+use crate::tcp_proxy::TCPProxy;
+
+/// Brings the devices found on the local network (MediaRenderers, typically) to the network we announce on.
+pub struct Reverse {
+    config: ReverseConfig,
+    announcer: DeviceAnnouncer,
+}
+
+impl Reverse {
+    pub fn new(config: ReverseConfig, interfaces: Interfaces, ipv6: bool) -> Result<Self> {
+        //Devices announced where they are found would be seen twice, under the same UDN.
+        let shared = config.search_interfaces.shared_with(&interfaces)?;
+
+        if !shared.is_empty() {
+            return Err(anyhow!(
+                "Reverse mode can't look for devices where it announces them ({}), set its iface to another interface.",
+                shared.join(", ")
+            ));
+        }
+
+        Ok(Reverse {
+            announcer: DeviceAnnouncer::new(
+                config.proxy,
+                config.period,
+                config.description_ttl,
+                interfaces,
+                ipv6,
+                true,
+            ),
+            config,
+        })
+    }
+
+    /// Searches for devices until `shutdown` is triggered, relaying the new ones, and those that moved.
+    pub async fn run(mut self, shutdown: CancellationToken) {
+        info!(target: "dlnaproxy", "Looking for '{}' on {:?}.", self.config.search_target, self.config.search_interfaces);
+
+        loop {
+            match search::search(&self.config.search_interfaces, &self.config.search_target).await {
+                Ok(found) => {
+                    for device in found {
+                        match self.announcer.location(&device.udn) {
+                            Some(location) if *location == device.location => continue,
+                            Some(location) => {
+                                info!(target: "dlnaproxy", "{} moved from {} to {}.", device.udn, location, device.location)
+                            }
+                            None => {
+                                info!(target: "dlnaproxy", "Found {} at {}.", device.udn, device.location)
+                            }
+                        }
+
+                        if let Err(e) = self.relay(&device.udn, &device.location, &shutdown).await {
+                            warn!(target: "dlnaproxy", "Failed to relay {}: {:#}", device.udn, e);
+                        }
+                    }
+                }
+                Err(e) => warn!(target: "dlnaproxy", "Search failed: {:#}", e),
+            }
+
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tokio::time::sleep(self.config.search_interval) => {}
+            }
+        }
+
+        self.announcer.join().await;
+    }
+
+    async fn relay(
+        &mut self,
+        udn: &str,
+        location: &Url,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let device_addr = lookup_host(location_authority(location)?)
+            .await?
+            .next()
+            .context("Unreachable LOCATION.")?;
+
+        self.announcer
+            .announce(udn, location, device_addr, shutdown)
+            .await
+    }
+}
+
+/// `host:port` of a LOCATION, as `lookup_host` takes it.
+fn location_authority(location: &Url) -> Result<String> {
+    let host = location.host_str().context("LOCATION without a host.")?;
+    let port = location
+        .port_or_known_default()
+        .context("LOCATION without a port.")?;
+
+    Ok(format!("{}:{}", host, port))
+}
+
+/// A device announced, along with what it takes to withdraw it.
+struct Announced {
+    location: Url,
+    /// Port of its proxy.
+    port: u16,
+    stop: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Announces devices of another network, each through a proxy of its own, on consecutive ports.
+pub struct DeviceAnnouncer {
+    /// Address of the first device's proxy.
+    proxy: SocketAddr,
+    next_port: u16,
+    period: Duration,
+    description_ttl: Duration,
+    /// Where the devices are announced.
+    interfaces: Interfaces,
+    ipv6: bool,
+    /// Whether the devices' proxies relay subscriptions.
+    events: bool,
+    /// By UDN.
+    devices: HashMap<String, Announced>,
+}
+
+impl DeviceAnnouncer {
+    pub fn new(
+        proxy: SocketAddr,
+        period: Duration,
+        description_ttl: Duration,
+        interfaces: Interfaces,
+        ipv6: bool,
+        events: bool,
+    ) -> Self {
+        DeviceAnnouncer {
+            proxy,
+            next_port: proxy.port(),
+            period,
+            description_ttl,
+            interfaces,
+            ipv6,
+            events,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// LOCATION the device `udn` is announced from, if it is.
+    pub fn location(&self, udn: &str) -> Option<&Url> {
+        self.devices.get(udn).map(|device| &device.location)
+    }
+
+    /// Announces the device `udn` described at `location`, its proxy connecting to `device_addr`.
+    /// A device announced already is withdrawn first, its new proxy taking the same port.
+    pub async fn announce(
+        &mut self,
+        udn: &str,
+        location: &Url,
+        device_addr: SocketAddr,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let port = match self.devices.remove(udn) {
+            Some(previous) => {
+                let port = previous.port;
+                withdraw(previous).await;
+                port
+            }
+            None => {
+                let port = self.next_port;
+
+                self.next_port = self
+                    .next_port
+                    .checked_add(1)
+                    .context("Ran out of proxy ports.")?;
+
+                port
+            }
+        };
+
+        let proxy_addr = SocketAddr::new(self.proxy.ip(), port);
+
+        let mut desc_url = location.clone();
+        desc_url.set_ip_host(proxy_addr.ip()).unwrap();
+        desc_url.set_port(Some(proxy_addr.port())).unwrap();
+
+        debug!(target: "dlnaproxy", "Desc URL: '{}', interval: {}s", desc_url, self.period.as_secs());
+
+        let endpoint = Endpoint {
+            desc_url,
+            broadcast_period: self.period,
+            description_ttl: self.description_ttl,
+            follow: None,
+        };
+
+        //Devices come and go, each gets SSDP sockets of its own rather than joining the static set of endpoints.
+        let ssdp = SSDPManager::new(
+            vec![endpoint],
+            Some(Duration::from_secs(2)),
+            self.interfaces.clone(),
+            self.ipv6,
+        )
+        .await?;
+
+        //The device keeps its own UDN: it is the same device, seen from another network.
+        let mut context = HttpContext::new(
+            location,
+            device_addr,
+            DescriptionOverrides::default(),
+            None,
+            None,
+        );
+
+        if !self.events {
+            context = context.without_events();
+        }
+
+        let stop = shutdown.child_token();
+
+        let proxy = TCPProxy::http(context)
+            .start(device_addr, proxy_addr, stop.clone())
+            .await?;
+
+        let announcing = {
+            let stop = stop.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = main_task(ssdp, stop).await {
+                    warn!(target: "dlnaproxy", "SSDP tasks failed: {:#}", e);
+                }
+            })
+        };
+
+        self.devices.insert(
+            udn.into(),
+            Announced {
+                location: location.clone(),
+                port,
+                stop,
+                tasks: vec![proxy, announcing],
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Waits for the proxies and SSDP tasks to stop, once shutdown is triggered.
+    pub async fn join(&mut self) {
+        for (_, device) in self.devices.drain() {
+            for task in device.tasks {
+                let _ = task.await;
+            }
+        }
+    }
+}
+
+async fn withdraw(device: Announced) {
+    device.stop.cancel();
+
+    for task in device.tasks {
+        let _ = task.await;
+    }
+}
@@ -1,71 +1,96 @@
 use log::{debug, info, warn};
-use tokio::net::UdpSocket;
-use tokio::{signal, time};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
 
-use std::borrow::Borrow as _;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{process, sync::Arc};
 
 use anyhow::Result;
 
-use crate::ssdp::utils::InteractiveSSDP;
-use crate::ssdp::SSDP_ADDRESS;
+use crate::ssdp::utils::{InteractiveSSDP, RemoteState};
+use crate::ssdp::SSDPSocket;
+
+/// First retry delay when the remote server can't be reached, doubled on every failure up to the broadcast period.
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(5);
 
 pub struct SSDPBroadcast {
-    ssdp_socket: Arc<UdpSocket>,
+    ssdp_sockets: Vec<Arc<SSDPSocket>>,
     ssdp_helper: Arc<InteractiveSSDP>,
 }
 
 impl SSDPBroadcast {
-    pub fn new(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<InteractiveSSDP>) -> Self {
+    pub fn new(ssdp_sockets: Vec<Arc<SSDPSocket>>, ssdp_helper: Arc<InteractiveSSDP>) -> Self {
         SSDPBroadcast {
-            ssdp_socket,
+            ssdp_sockets,
             ssdp_helper,
         }
     }
 
     pub async fn do_ssdp_alive(&self) -> Result<()> {
-        self.ssdp_helper
-            .send_alive(self.ssdp_socket.borrow(), SSDP_ADDRESS)
-            .await
+        self.ssdp_helper.send_alive(&self.ssdp_sockets).await
     }
-}
-
-pub async fn broadcast_task(broadcaster: Arc<SSDPBroadcast>, period: Duration) {
-    let _handle = tokio::spawn(ctrlc_handler(broadcaster.clone()));
-
-    debug!(target: "dlnaproxy", "About to schedule broadcast every {}s", period.as_secs());
-
-    let mut interval = time::interval(period);
 
-    loop {
-        if let Err(msg) = broadcaster.do_ssdp_alive().await {
-            warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}", msg);
-            break;
-        } else {
-            info!(target: "dlnaproxy", "Broadcasted on local SSDP channel!");
+    pub async fn do_ssdp_byebye(&self) {
+        if let Err(msg) = self.ssdp_helper.send_byebye(&self.ssdp_sockets).await {
+            warn!(target: "dlnaproxy", "Failed to send ssdp:byebye: {}", msg);
         }
-
-        interval.tick().await;
     }
 }
 
-pub async fn ctrlc_handler(broadcaster: Arc<SSDPBroadcast>) -> Result<()> {
-    debug!(target:"dlnaproxy", "SIGINT handler waiting...");
-
-    signal::ctrl_c().await?;
-
-    let socket = broadcaster.ssdp_socket.clone();
+pub async fn broadcast_task(
+    broadcaster: Arc<SSDPBroadcast>,
+    period: Duration,
+    shutdown: CancellationToken,
+) {
+    debug!(target: "dlnaproxy", "About to schedule broadcast every {}s", period.as_secs());
 
     let helper = broadcaster.ssdp_helper.clone();
+    let mut retry_delay = RETRY_MIN_DELAY;
 
-    debug!(target:"dlnaproxy", "SIGINT handler triggered, sending ssdp:bybye !");
-
-    if let Err(msg) = helper.send_byebye(&socket, SSDP_ADDRESS).await {
-        warn!(target: "dlnaproxy", "Failed to send ssdp:byebye: {}", msg);
+    loop {
+        let alive = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            alive = broadcaster.do_ssdp_alive() => alive,
+        };
+
+        let next_attempt = match alive {
+            Ok(()) => {
+                if helper.transition(RemoteState::Available) == RemoteState::Unreachable {
+                    info!(target: "dlnaproxy", "Remote server is reachable again, resuming announcements.");
+                }
+
+                info!(target: "dlnaproxy", "Broadcasted on local SSDP channel!");
+
+                retry_delay = RETRY_MIN_DELAY;
+                period
+            }
+            Err(msg) => {
+                warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}", msg);
+
+                if helper.transition(RemoteState::Unreachable) == RemoteState::Available {
+                    warn!(target: "dlnaproxy", "Remote server became unreachable, withdrawing it from the local network.");
+
+                    broadcaster.do_ssdp_byebye().await;
+                }
+
+                let delay = retry_delay;
+                retry_delay = (retry_delay * 2).min(period);
+
+                debug!(target: "dlnaproxy", "Retrying in {}s.", delay.as_secs());
+                delay
+            }
+        };
+
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = time::sleep(next_attempt) => {}
+        }
     }
 
-    info!(target: "dlnaproxy", "Exiting !");
+    //Nothing to withdraw if the server was never announced, or already withdrawn.
+    if helper.state() == RemoteState::Available {
+        debug!(target:"dlnaproxy", "Shutting down, sending ssdp:byebye !");
 
-    process::exit(0);
+        broadcaster.do_ssdp_byebye().await;
+    }
 }
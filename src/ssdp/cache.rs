@@ -0,0 +1,199 @@
+use log::{debug, trace, warn};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, SERVER};
+use reqwest::StatusCode;
+
+use crate::ssdp::utils::{parse_endpoint_info, EndpointInfo};
+
+/// How long a stale entry is served as is after failing to revalidate it, before trying again.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+struct CachedInfo {
+    info: Arc<EndpointInfo>,
+    fetched_at: Instant,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+}
+
+/// Remote device description, fetched once and trusted for `ttl`.
+/// Past that, the stale copy keeps being served while it is revalidated in the background.
+pub struct EndpointCache {
+    http_client: reqwest::Client,
+    remote_desc_url: String,
+    ttl: Duration,
+    entry: RwLock<Option<CachedInfo>>,
+    refresh_lock: tokio::sync::Mutex<()>,
+    /// Whether a background revalidation is under way.
+    revalidating: AtomicBool,
+    /// When revalidating last failed, until it succeeds again.
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl EndpointCache {
+    pub fn new(http_client: reqwest::Client, remote_desc_url: &str, ttl: Duration) -> Self {
+        EndpointCache {
+            http_client,
+            remote_desc_url: remote_desc_url.into(),
+            ttl,
+            entry: RwLock::new(None),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            revalidating: AtomicBool::new(false),
+            failed_at: Mutex::new(None),
+        }
+    }
+
+    /// Last known endpoint info, however old it is.
+    pub fn cached(&self) -> Option<Arc<EndpointInfo>> {
+        self.entry
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|cached| cached.info.clone())
+    }
+
+    /// Endpoint info, only hitting the remote server when nothing has been cached yet.
+    pub async fn get(self: &Arc<Self>) -> Result<Arc<EndpointInfo>> {
+        let cached = self
+            .entry
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|cached| (cached.info.clone(), cached.fetched_at.elapsed() < self.ttl));
+
+        match cached {
+            Some((info, true)) => Ok(info),
+            Some((info, false)) => {
+                //A single revalidation at a time, and none for a while after one failed: the remote server may be
+                //unreachable, each attempt then taking as long as the connection timeout.
+                let failed_recently = self
+                    .failed_at
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|at| at.elapsed() < RETRY_DELAY);
+
+                if failed_recently || self.revalidating.swap(true, Ordering::AcqRel) {
+                    return Ok(info);
+                }
+
+                let cache = self.clone();
+                let requested_at = Instant::now();
+
+                tokio::spawn(async move {
+                    if let Err(msg) = cache.revalidate(requested_at).await {
+                        warn!(target: "dlnaproxy", "Failed to revalidate remote server's info: {}", msg);
+                    }
+
+                    cache.revalidating.store(false, Ordering::Release);
+                });
+
+                Ok(info)
+            }
+            None => self.revalidate(Instant::now()).await,
+        }
+    }
+
+    /// Revalidates the cached info with the remote server, regardless of its age.
+    pub async fn refresh(&self) -> Result<Arc<EndpointInfo>> {
+        self.revalidate(Instant::now()).await
+    }
+
+    async fn revalidate(&self, requested_at: Instant) -> Result<Arc<EndpointInfo>> {
+        let _guard = self.refresh_lock.lock().await;
+
+        //Someone else failed to reach the remote server while we were waiting for the lock.
+        if self
+            .failed_at
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at >= requested_at)
+        {
+            return Err(anyhow!(
+                "Remote server just failed to serve its description."
+            ));
+        }
+
+        let fetched = self.fetch(requested_at).await;
+
+        *self.failed_at.lock().unwrap() = fetched.is_err().then(Instant::now);
+
+        fetched
+    }
+
+    async fn fetch(&self, requested_at: Instant) -> Result<Arc<EndpointInfo>> {
+        //Someone else refreshed the entry while we were waiting for the lock.
+        let (etag, last_modified) = {
+            let entry = self.entry.read().unwrap();
+
+            match entry.as_ref() {
+                Some(cached) if cached.fetched_at >= requested_at => {
+                    return Ok(cached.info.clone());
+                }
+                Some(cached) => (cached.etag.clone(), cached.last_modified.clone()),
+                None => (None, None),
+            }
+        };
+
+        trace!(target: "dlnaproxy", "Fetching remote server's info.");
+
+        let mut request = self.http_client.get(&self.remote_desc_url);
+
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+
+        if let Some(last_modified) = last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let endpoint_response = request
+            .send()
+            .await
+            .context("Failed to get description of remote endpoint.")?;
+
+        if endpoint_response.status() == StatusCode::NOT_MODIFIED {
+            let mut entry = self.entry.write().unwrap();
+
+            if let Some(cached) = entry.as_mut() {
+                debug!(target: "dlnaproxy", "Remote server's description hasn't changed.");
+
+                cached.fetched_at = Instant::now();
+                return Ok(cached.info.clone());
+            }
+        }
+
+        let endpoint_response = endpoint_response
+            .error_for_status()
+            .context("Remote endpoint refused to serve its description.")?;
+
+        let headers = endpoint_response.headers();
+
+        let server_ua = headers
+            .get(SERVER)
+            .map(|hv| String::from_utf8_lossy(hv.as_bytes()).to_string())
+            .unwrap_or_else(|| "DLNAProxy/1.0".into());
+
+        let etag = headers.get(ETAG).cloned();
+        let last_modified = headers.get(LAST_MODIFIED).cloned();
+
+        let body = endpoint_response
+            .text()
+            .await
+            .context("Failed to parse response's body as text.")?;
+
+        let info = Arc::new(parse_endpoint_info(&body, server_ua)?);
+
+        *self.entry.write().unwrap() = Some(CachedInfo {
+            info: info.clone(),
+            fetched_at: Instant::now(),
+            etag,
+            last_modified,
+        });
+
+        Ok(info)
+    }
+}
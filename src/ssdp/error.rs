@@ -4,4 +4,13 @@ use thiserror::Error;
 pub enum Error {
     #[error("No SSDP method found while parsing packet.")]
     NoSSDPMethod,
+
+    #[error("Missing {0} header.")]
+    MissingHeader(&'static str),
+
+    #[error("Unexpected MAN header: '{0}'.")]
+    BadManHeader(String),
+
+    #[error("Invalid MX header: '{0}'.")]
+    BadMxHeader(String),
 }
@@ -0,0 +1,160 @@
+use log::{debug, info, trace, warn};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::Url;
+
+use crate::ssdp::broadcast::SSDPBroadcast;
+use crate::ssdp::listener::parse_ssdp;
+use crate::ssdp::utils::{InteractiveSSDP, RemoteState};
+use crate::ssdp::SSDPSocket;
+
+/// A server announces itself with a burst of NOTIFYs, one per device and service, we only act on the first one.
+const BURST: Duration = Duration::from_secs(5);
+
+/// UDA's minimum CACHE-CONTROL, for announcements that don't carry a usable one.
+const DEFAULT_MAX_AGE: u64 = 1800;
+
+/// Longest the remote server stays announced without hearing from it, whatever max-age it announces.
+const MAX_MAX_AGE: u64 = 24 * 3600;
+
+/// Announces a remote server whenever it announces itself on another interface, instead of checking on it periodically.
+pub struct SSDPFollower {
+    /// Bound to the interface the remote server's announcements come in on.
+    ssdp: SSDPSocket,
+    /// The remote server's description URL, which its announcements carry as LOCATION.
+    remote_url: Url,
+    broadcaster: Arc<SSDPBroadcast>,
+    ssdp_helper: Arc<InteractiveSSDP>,
+}
+
+impl SSDPFollower {
+    pub fn new(
+        ssdp: SSDPSocket,
+        remote_url: Url,
+        broadcaster: Arc<SSDPBroadcast>,
+        ssdp_helper: Arc<InteractiveSSDP>,
+    ) -> Self {
+        SSDPFollower {
+            ssdp,
+            remote_url,
+            broadcaster,
+            ssdp_helper,
+        }
+    }
+
+    /// Whether `location` is the remote server's, as other devices may announce themselves there too.
+    fn is_remote(&self, location: &str) -> bool {
+        Url::parse(location).is_ok_and(|location| {
+            location.host() == self.remote_url.host()
+                && location.port_or_known_default() == self.remote_url.port_or_known_default()
+        })
+    }
+
+    async fn alive(&self) {
+        match self.broadcaster.do_ssdp_alive().await {
+            Ok(()) => {
+                if self.ssdp_helper.transition(RemoteState::Available) != RemoteState::Available {
+                    info!(target: "dlnaproxy", "Remote server is up, announcing it on local SSDP channel!");
+                }
+            }
+            Err(msg) => warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}", msg),
+        }
+    }
+
+    async fn withdraw(&self) {
+        if self.ssdp_helper.transition(RemoteState::Unreachable) == RemoteState::Available {
+            self.broadcaster.do_ssdp_byebye().await;
+        }
+    }
+}
+
+pub async fn follow_task(follower: SSDPFollower, shutdown: CancellationToken) {
+    debug!(target: "dlnaproxy", "Following announcements of {}.", follower.remote_url);
+
+    //The server may well be up already, in which case we would only hear from it at its next announcement.
+    follower.alive().await;
+
+    //When the last announcement heard of expires.
+    let mut expires = (follower.ssdp_helper.state() == RemoteState::Available)
+        .then(|| Instant::now() + Duration::from_secs(DEFAULT_MAX_AGE));
+    let mut last_alive: Option<Instant> = None;
+
+    let mut buffer = [0; 2048];
+
+    loop {
+        let (length, sender) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = time::sleep_until(expires.unwrap_or_else(Instant::now)), if expires.is_some() => {
+                warn!(target: "dlnaproxy", "Remote server's announcements expired, withdrawing it from the local network.");
+
+                expires = None;
+                follower.withdraw().await;
+                continue;
+            }
+            received = follower.ssdp.socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    warn!(target: "dlnaproxy", "Failed to receive SSDP packet: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let Ok((method, headers)) = parse_ssdp(&buffer[..length]) else {
+            continue;
+        };
+
+        let header = |name: &str| headers.get(name).map_or("", |value| value.trim());
+
+        if method != "NOTIFY" || !follower.is_remote(header("LOCATION")) {
+            continue;
+        }
+
+        trace!(target: "dlnaproxy", "Heard {} for {} from {}.", header("NTS"), header("USN"), sender);
+
+        match header("NTS") {
+            "ssdp:alive" => {
+                let max_age = header("CACHE-CONTROL")
+                    .split(',')
+                    .find_map(|directive| directive.trim().strip_prefix("max-age"))
+                    .and_then(|value| value.trim().strip_prefix('='))
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_MAX_AGE)
+                    .min(MAX_MAX_AGE);
+
+                expires = Some(Instant::now() + Duration::from_secs(max_age));
+
+                if last_alive.is_some_and(|at| at.elapsed() < BURST) {
+                    continue;
+                }
+
+                last_alive = Some(Instant::now());
+
+                follower.ssdp_helper.set_cache_max_age(max_age as usize);
+                follower.alive().await;
+            }
+            "ssdp:byebye" => {
+                expires = None;
+                //A restarting server says goodbye, then announces itself again right away.
+                last_alive = None;
+
+                if follower.ssdp_helper.state() == RemoteState::Available {
+                    info!(target: "dlnaproxy", "Remote server said goodbye, withdrawing it from the local network.");
+                }
+
+                follower.withdraw().await;
+            }
+            _ => {}
+        }
+    }
+
+    if follower.ssdp_helper.state() == RemoteState::Available {
+        debug!(target:"dlnaproxy", "Shutting down, sending ssdp:byebye !");
+
+        follower.broadcaster.do_ssdp_byebye().await;
+    }
+}
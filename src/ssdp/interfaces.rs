@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Context, Result};
+
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::{if_nametoindex, InterfaceFlags};
+
+/// Network interfaces on which we announce.
+#[derive(Clone, Debug, Default)]
+pub enum Interfaces {
+    /// Whatever the system routes multicast through.
+    #[default]
+    Default,
+    /// Every interface that is up and multicast-capable, loopback aside.
+    All,
+    Named(Vec<String>),
+}
+
+/// Addresses of an interface, used to make a LOCATION pointing at a wildcard address reachable from its network.
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceAddrs {
+    pub v4: Option<Ipv4Addr>,
+    /// Link-local addresses aside, as they can't be used in URLs without a zone.
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl InterfaceAddrs {
+    /// Address of the same family as `like`, or of the other one if the interface has none.
+    pub fn host_like(&self, like: IpAddr) -> Option<IpAddr> {
+        let v4 = self.v4.map(IpAddr::V4);
+        let v6 = self.v6.map(IpAddr::V6);
+
+        match like {
+            IpAddr::V4(_) => v4.or(v6),
+            IpAddr::V6(_) => v6.or(v4),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub index: u32,
+    pub addrs: InterfaceAddrs,
+}
+
+impl Interfaces {
+    fn wants(&self, name: &str, flags: InterfaceFlags) -> bool {
+        match self {
+            Interfaces::Default => false,
+            Interfaces::All => {
+                flags.contains(InterfaceFlags::IFF_UP | InterfaceFlags::IFF_MULTICAST)
+                    && !flags.contains(InterfaceFlags::IFF_LOOPBACK)
+            }
+            Interfaces::Named(names) => names.iter().any(|n| n == name),
+        }
+    }
+
+    /// Looks the interfaces up, `None` meaning that the system's default one should be used.
+    pub fn resolve(&self) -> Result<Option<Vec<NetworkInterface>>> {
+        if let Interfaces::Default = self {
+            return Ok(None);
+        }
+
+        let mut found: BTreeMap<String, InterfaceAddrs> = BTreeMap::new();
+
+        for ifaddr in getifaddrs().context("Failed to list network interfaces.")? {
+            if !self.wants(&ifaddr.interface_name, ifaddr.flags) {
+                continue;
+            }
+
+            let addrs = found.entry(ifaddr.interface_name).or_default();
+
+            let Some(address) = ifaddr.address else {
+                continue;
+            };
+
+            if let Some(v4) = address.as_sockaddr_in() {
+                addrs.v4.get_or_insert(v4.ip());
+            } else if let Some(v6) = address.as_sockaddr_in6() {
+                if !is_unicast_link_local(&v6.ip()) {
+                    addrs.v6.get_or_insert(v6.ip());
+                }
+            }
+        }
+
+        if let Interfaces::Named(names) = self {
+            if let Some(missing) = names.iter().find(|name| !found.contains_key(*name)) {
+                return Err(anyhow!("Unknown network interface '{}'.", missing));
+            }
+        }
+
+        if found.is_empty() {
+            return Err(anyhow!("No multicast-capable network interface found."));
+        }
+
+        found
+            .into_iter()
+            .map(|(name, addrs)| {
+                let index = if_nametoindex(name.as_str())
+                    .with_context(|| format!("Unknown network interface '{}'.", name))?;
+
+                Ok(NetworkInterface { name, index, addrs })
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+}
+
+impl Interfaces {
+    /// Names of the interfaces in common with `other`, the default one being whichever the system sends multicast through.
+    pub fn shared_with(&self, other: &Interfaces) -> Result<Vec<String>> {
+        if let (Interfaces::Default, Interfaces::Default) = (self, other) {
+            return Ok(vec!["the default interface".into()]);
+        }
+
+        let ours = self.names()?;
+
+        Ok(other.names()?.intersection(&ours).cloned().collect())
+    }
+
+    fn names(&self) -> Result<BTreeSet<String>> {
+        if let Some(interfaces) = self.resolve()? {
+            return Ok(interfaces.into_iter().map(|iface| iface.name).collect());
+        }
+
+        let Some(default_addr) = super::local_addr_facing(super::SSDP_ADDRESS.into()) else {
+            return Ok(BTreeSet::new());
+        };
+
+        Ok(getifaddrs()
+            .context("Failed to list network interfaces.")?
+            .filter(|ifaddr| {
+                ifaddr
+                    .address
+                    .and_then(|address| address.as_sockaddr_in().map(|v4| IpAddr::V4(v4.ip())))
+                    == Some(default_addr)
+            })
+            .map(|ifaddr| ifaddr.interface_name)
+            .collect())
+    }
+}
+
+pub fn is_unicast_link_local(addr: &Ipv6Addr) -> bool {
+    (addr.segments()[0] & 0xffc0) == 0xfe80
+}
@@ -2,31 +2,43 @@ use log::debug;
 use log::{info, trace, warn};
 
 use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
-use tokio::net::UdpSocket;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use rand::Rng as _;
 
 use httparse::{Request, EMPTY_HEADER};
 
 use anyhow::Context;
 use anyhow::Result;
 
+use super::error::Error;
 use crate::ssdp::utils::InteractiveSSDP;
+use crate::ssdp::SSDPSocket;
+
+/// UPnP 1.1: MX values below 1 are treated as 1, values above 5 as 5.
+pub const MIN_MX: u64 = 1;
+pub const MAX_MX: u64 = 5;
+
+/// Responses waiting for their delay to pass at most, further M-SEARCHes being ignored meanwhile.
+const MAX_PENDING_RESPONSES: usize = 64;
 
 /*
     SSDP RFC for reference: https://tools.ietf.org/html/draft-cai-ssdp-v1-03
 */
 
-fn parse_ssdp(buffer: &[u8]) -> Result<(String, HashMap<String, Cow<'_, str>>)> {
+pub fn parse_ssdp(buffer: &[u8]) -> Result<(String, HashMap<String, Cow<'_, str>>)> {
     let mut headers = [EMPTY_HEADER; 16];
     let mut req = Request::new(&mut headers);
 
     req.parse(buffer)
         .context("Failed to parse packet as SSDP.")?;
 
-    let method = req
-        .method
-        .map(String::from)
-        .ok_or(super::error::Error::NoSSDPMethod)?;
+    let method = req.method.map(String::from).ok_or(Error::NoSSDPMethod)?;
 
     let mut header_map: HashMap<String, Cow<'_, str>> = HashMap::with_capacity(headers.len());
     let mut i = 0;
@@ -41,16 +53,22 @@ fn parse_ssdp(buffer: &[u8]) -> Result<(String, HashMap<String, Cow<'_, str>>)>
     Ok((method, header_map))
 }
 
-pub async fn listen_task(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<InteractiveSSDP>) {
+pub async fn listen_task(
+    ssdp_socket: Arc<SSDPSocket>,
+    ssdp_helpers: Arc<[Arc<InteractiveSSDP>]>,
+    shutdown: CancellationToken,
+) {
     debug!(target: "dlnaproxy", "Listen task up and running!");
 
+    let pending = Arc::new(Semaphore::new(MAX_PENDING_RESPONSES));
+
     loop {
         let mut buffer: [u8; 1024] = [0; 1024];
 
-        let (bytes_read, src_addr) = ssdp_socket
-            .recv_from(&mut buffer)
-            .await
-            .expect("failed to read!");
+        let (bytes_read, src_addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = ssdp_socket.socket.recv_from(&mut buffer) => received.expect("failed to read!"),
+        };
 
         trace!(target: "dlnaproxy", "Read {amount} bytes sent by {sender}.", amount=bytes_read, sender=src_addr);
 
@@ -62,20 +80,106 @@ pub async fn listen_task(ssdp_socket: Arc<UdpSocket>, ssdp_helper: Arc<Interacti
             }
         };
 
-        let st_header = ssdp_headers.get("ST");
-        let _man_header = ssdp_headers.get("MAN");
+        if ssdp_method != "M-SEARCH" {
+            continue;
+        }
+
+        let search = match parse_search(&ssdp_headers) {
+            Ok(search) => search,
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Ignoring M-SEARCH from {sender}: {error}", sender=src_addr, error=e);
+                continue;
+            }
+        };
 
-        //We have a valid ssdp:discover request, although the rfc is soooooo vague it hurts.
-        if let Some(header) = st_header {
-            if ssdp_method == "M-SEARCH" && header == "urn:schemas-upnp-org:device:MediaServer:1" {
-                info!(target: "dlnaproxy", "Responding to a M-SEARCH request for a MediaServer from {sender}.", sender=src_addr);
+        debug!(target: "dlnaproxy", "Received a M-SEARCH request for '{target}' from {sender} (MX: {mx}).", target=search.search_target, sender=src_addr, mx=search.max_wait);
 
-                if let Err(msg) = ssdp_helper.send_ok(&ssdp_socket, src_addr).await {
-                    warn!(target: "dlnaproxy", "Couldn't send ssdp:alive: {}", msg);
-                } else {
-                    info!(target: "dlnaproxy", "Sent ssdp:ok on local SSDP channel!");
-                }
+        //Responses are spread over [0, MX] so that we don't add to a response storm, without holding up the receive loop.
+        for ssdp_helper in ssdp_helpers.iter() {
+            if !ssdp_helper.answers(&search.search_target) {
+                continue;
             }
+
+            let Ok(permit) = pending.clone().try_acquire_owned() else {
+                debug!(target: "dlnaproxy", "Too many pending responses, ignoring M-SEARCH from {}.", src_addr);
+                break;
+            };
+
+            let delay =
+                Duration::from_millis(rand::thread_rng().gen_range(0..=search.max_wait * 1000));
+
+            tokio::spawn(respond_task(
+                ssdp_socket.clone(),
+                ssdp_helper.clone(),
+                src_addr,
+                search.search_target.clone(),
+                delay,
+                permit,
+                shutdown.clone(),
+            ));
+        }
+    }
+}
+
+struct SearchRequest {
+    search_target: String,
+    /// Upper bound of the response delay, in seconds.
+    max_wait: u64,
+}
+
+fn parse_search(headers: &HashMap<String, Cow<'_, str>>) -> Result<SearchRequest, Error> {
+    let man = headers.get("MAN").ok_or(Error::MissingHeader("MAN"))?;
+
+    if man.trim() != "\"ssdp:discover\"" {
+        return Err(Error::BadManHeader(man.to_string()));
+    }
+
+    let search_target = headers
+        .get("ST")
+        .map(|st| st.trim().to_string())
+        .ok_or(Error::MissingHeader("ST"))?;
+
+    //MX is only mandatory for multicast searches, unicast ones get an immediate answer.
+    let max_wait = match headers.get("MX") {
+        None => 0,
+        Some(mx) => mx
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| Error::BadMxHeader(mx.to_string()))?
+            .clamp(MIN_MX, MAX_MX),
+    };
+
+    Ok(SearchRequest {
+        search_target,
+        max_wait,
+    })
+}
+
+async fn respond_task(
+    ssdp_socket: Arc<SSDPSocket>,
+    ssdp_helper: Arc<InteractiveSSDP>,
+    src_addr: SocketAddr,
+    search_target: String,
+    delay: Duration,
+    _permit: OwnedSemaphorePermit,
+    shutdown: CancellationToken,
+) {
+    //No point in answering once we are about to say goodbye.
+    tokio::select! {
+        _ = shutdown.cancelled() => return,
+        _ = time::sleep(delay) => {}
+    }
+
+    match ssdp_helper
+        .send_ok(&ssdp_socket, src_addr, &search_target)
+        .await
+    {
+        Ok(0) => {}
+        Ok(count) => {
+            info!(target: "dlnaproxy", "Sent {count} ssdp:ok to {sender} on local SSDP channel!", count=count, sender=src_addr);
+        }
+        Err(msg) => {
+            warn!(target: "dlnaproxy", "Couldn't send ssdp:ok: {}", msg);
         }
     }
 }
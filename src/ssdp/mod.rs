@@ -1,44 +1,148 @@
-use std::{net::Ipv4Addr, os::fd::AsFd as _, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::fd::AsRawFd as _,
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::Url;
 use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
-use log::info;
+use log::{info, warn};
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
 use nix::sys::socket::sockopt::BindToDevice;
 
-use nix::sys::socket::{self, sockopt::ReuseAddr};
+use nix::sys::socket::{
+    self,
+    sockopt::{Ipv6V6Only, ReuseAddr},
+    AddressFamily, SockFlag, SockType, SockaddrStorage,
+};
 
 use broadcast::broadcast_task;
+use follow::{follow_task, SSDPFollower};
 use listener::listen_task;
 
 use crate::ssdp::broadcast::SSDPBroadcast;
+use crate::ssdp::interfaces::{InterfaceAddrs, NetworkInterface};
 use crate::ssdp::utils::InteractiveSSDP;
 
+pub use interfaces::Interfaces;
+
 pub mod broadcast;
+pub mod cache;
 mod error;
+pub mod follow;
+mod interfaces;
 pub mod listener;
 pub mod packet;
+pub mod search;
 pub mod utils;
 
-pub static DUMMY_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(0, 0, 0, 0), 1900);
+pub const SSDP_PORT: u16 = 1900;
 
-pub static SSDP_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+pub static SSDP_ADDRESS: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), SSDP_PORT);
 
-pub struct SSDPManager {
+/// Link-local and site-local scopes of the IPv6 SSDP multicast group.
+pub static SSDP_V6_ADDRESSES: [Ipv6Addr; 2] = [
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc),
+    Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xc),
+];
+
+/// A socket bound to the SSDP port, along with the multicast groups we announce on through it.
+pub struct SSDPSocket {
+    pub socket: UdpSocket,
+    pub groups: Vec<SocketAddr>,
+    /// Addresses of the interface the socket is bound to, none when left to the system.
+    pub iface_addrs: InterfaceAddrs,
+}
+
+impl SSDPSocket {
+    /// Address to advertise to `dest` instead of the wildcard address `bound` a proxy listens on.
+    pub fn location_host(&self, bound: IpAddr, dest: SocketAddr) -> Option<IpAddr> {
+        let from_iface = match bound {
+            //An IPv4 listener can't be reached over IPv6.
+            IpAddr::V4(_) => self.iface_addrs.v4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.iface_addrs.host_like(self.groups[0].ip()),
+        };
+
+        from_iface.or_else(|| {
+            //Left to the system, the interface is whichever one it routes `dest` through.
+            let facing = match (bound, dest) {
+                (IpAddr::V4(_), SocketAddr::V6(_)) => SSDP_ADDRESS.into(),
+                _ => dest,
+            };
+
+            let found = local_addr_facing(facing);
+
+            match (bound, found) {
+                //No usable IPv6 address, the listener may still be reached over IPv4.
+                (IpAddr::V6(_), None) if dest.is_ipv6() => local_addr_facing(SSDP_ADDRESS.into()),
+                _ => found,
+            }
+        })
+    }
+}
+
+/// Local address the system would send from to reach `dest`, learned by connecting a UDP socket (nothing is sent).
+fn local_addr_facing(dest: SocketAddr) -> Option<IpAddr> {
+    let unspecified: SocketAddr = match dest {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let probe = std::net::UdpSocket::bind(unspecified).ok()?;
+    probe.connect(dest).ok()?;
+
+    let local = probe.local_addr().ok()?.ip();
+
+    //Link-local IPv6 addresses can't be used in URLs without a zone.
+    match local {
+        IpAddr::V6(v6) if interfaces::is_unicast_link_local(&v6) => None,
+        local if local.is_unspecified() => None,
+        local => Some(local),
+    }
+}
+
+/// A remote server we announce on the local network.
+pub struct Endpoint {
+    /// URL advertised in LOCATION, pointing either to the remote server or to our proxy.
+    pub desc_url: Url,
+    pub broadcast_period: Duration,
+    /// How long the remote description is trusted before being revalidated.
+    pub description_ttl: Duration,
+    /// Announce the server when it announces itself, rather than every `broadcast_period`.
+    pub follow: Option<Follow>,
+}
+
+/// Where to hear a remote server's own announcements, multicast being routed from its network.
+pub struct Follow {
+    pub iface: String,
+    /// The remote server's description URL, which its announcements carry as LOCATION.
+    pub remote_url: Url,
+}
+
+struct ManagedEndpoint {
     broadcast_period: Duration,
-    socket: Arc<UdpSocket>,
     interactive_ssdp: Arc<InteractiveSSDP>,
     broadcaster: Arc<SSDPBroadcast>,
+    follower: Option<SSDPFollower>,
+}
+
+pub struct SSDPManager {
+    sockets: Vec<Arc<SSDPSocket>>,
+    endpoints: Vec<ManagedEndpoint>,
 }
 
 impl SSDPManager {
     pub async fn new(
-        endpoint_desc_url: &str,
-        broadcast_period: Duration,
+        endpoints: Vec<Endpoint>,
         connect_timeout: Option<Duration>,
-        broadcast_iface: Option<String>,
+        interfaces: Interfaces,
+        ipv6: bool,
     ) -> Result<Self> {
         let mut http_client = reqwest::Client::builder();
 
@@ -48,43 +152,187 @@ impl SSDPManager {
 
         let http_client = http_client.build().context("Failed to build HTTP client")?;
 
-        let socket = ssdp_socket(broadcast_iface).await?;
+        let sockets = ssdp_sockets(&interfaces, ipv6)?;
+
+        let endpoints = endpoints
+            .into_iter()
+            .map(|endpoint| -> Result<ManagedEndpoint> {
+                let cache_max_age = match endpoint.broadcast_period.as_secs() {
+                    n if n < 20 => 20,
+                    n => n * 2,
+                } as usize;
+
+                let interactive_ssdp = Arc::new(InteractiveSSDP::new(
+                    http_client.clone(),
+                    &endpoint.desc_url,
+                    cache_max_age,
+                    endpoint.description_ttl,
+                ));
+
+                let broadcaster = Arc::new(SSDPBroadcast::new(
+                    sockets.clone(),
+                    interactive_ssdp.clone(),
+                ));
+
+                let follower = endpoint
+                    .follow
+                    .map(|follow| -> Result<SSDPFollower> {
+                        Ok(SSDPFollower::new(
+                            follow_socket(&follow.iface)?,
+                            follow.remote_url,
+                            broadcaster.clone(),
+                            interactive_ssdp.clone(),
+                        ))
+                    })
+                    .transpose()?;
+
+                Ok(ManagedEndpoint {
+                    broadcast_period: endpoint.broadcast_period,
+                    interactive_ssdp,
+                    broadcaster,
+                    follower,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(SSDPManager { sockets, endpoints })
+    }
+}
+
+fn ssdp_sockets(interfaces: &Interfaces, ipv6: bool) -> Result<Vec<Arc<SSDPSocket>>> {
+    let Some(interfaces) = interfaces.resolve()? else {
+        return iface_sockets(None, ipv6);
+    };
+
+    let mut sockets = Vec::new();
+
+    for iface in &interfaces {
+        info!(target: "dlnaproxy", "Announcing on {} ({:?}).", iface.name, iface.addrs);
+
+        sockets.extend(iface_sockets(Some(iface), ipv6)?);
+    }
+
+    Ok(sockets)
+}
 
-        let cache_max_age = match broadcast_period.as_secs() {
-            n if n < 20 => 20,
-            n => n * 2,
-        } as usize;
+/// One socket per address family on `iface`, or on the system's default interface.
+fn iface_sockets(iface: Option<&NetworkInterface>, ipv6: bool) -> Result<Vec<Arc<SSDPSocket>>> {
+    let mut sockets = Vec::with_capacity(2);
 
-        let interactive_ssdp = Arc::new(InteractiveSSDP::new(
-            http_client,
-            endpoint_desc_url,
-            cache_max_age,
+    match iface {
+        Some(iface) if iface.addrs.v4.is_none() => {
+            warn!(target: "dlnaproxy", "{} has no IPv4 address, not announcing over IPv4 there.", iface.name)
+        }
+        _ => sockets.push(Arc::new(ssdp_socket_v4(iface)?)),
+    }
+
+    //IPv4 is what most clients use, don't give up on it because IPv6 isn't available.
+    if ipv6 {
+        match ssdp_socket_v6(iface) {
+            Ok(socket) => sockets.push(Arc::new(socket)),
+            Err(e) => {
+                warn!(target: "dlnaproxy", "IPv6 SSDP unavailable, announcing over IPv4 only: {:#}", e)
+            }
+        }
+    }
+
+    if sockets.is_empty() {
+        return Err(anyhow!("Nothing to announce on."));
+    }
+
+    Ok(sockets)
+}
+
+/// Remote announcements are only followed over IPv4.
+fn follow_socket(iface: &str) -> Result<SSDPSocket> {
+    let iface = Interfaces::Named(vec![iface.into()])
+        .resolve()?
+        .and_then(|interfaces| interfaces.into_iter().next())
+        .context("No interface to follow announcements on.")?;
+
+    if iface.addrs.v4.is_none() {
+        return Err(anyhow!(
+            "{} has no IPv4 address to follow announcements on.",
+            iface.name
         ));
+    }
 
-        let broadcaster = Arc::new(SSDPBroadcast::new(socket.clone(), interactive_ssdp.clone()));
+    info!(target: "dlnaproxy", "Following announcements on {}.", iface.name);
 
-        Ok(SSDPManager {
-            broadcast_period,
-            socket,
-            interactive_ssdp,
-            broadcaster,
-        })
+    ssdp_socket_v4(Some(&iface))
+}
+
+pub fn ssdp_socket_v4(iface: Option<&NetworkInterface>) -> Result<SSDPSocket> {
+    let socket = bind_ssdp_socket(
+        (Ipv4Addr::UNSPECIFIED, SSDP_PORT).into(),
+        iface.map(|iface| iface.name.as_str()),
+    )?;
+
+    //The group must be joined on the interface we are bound to, not whichever one the system would pick.
+    let iface_addr = iface
+        .and_then(|iface| iface.addrs.v4)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+    socket
+        .join_multicast_v4(SSDP_ADDRESS.0, iface_addr)
+        .context("Failed to join SSDP multicast group.")?;
+
+    Ok(SSDPSocket {
+        socket,
+        groups: vec![SSDP_ADDRESS.into()],
+        iface_addrs: iface.map(|iface| iface.addrs.clone()).unwrap_or_default(),
+    })
+}
+
+fn ssdp_socket_v6(iface: Option<&NetworkInterface>) -> Result<SSDPSocket> {
+    //Interface 0 lets the system pick one.
+    let iface_index = iface.map_or(0, |iface| iface.index);
+
+    let socket = bind_ssdp_socket(
+        (Ipv6Addr::UNSPECIFIED, SSDP_PORT).into(),
+        iface.map(|iface| iface.name.as_str()),
+    )?;
+
+    let mut groups = Vec::with_capacity(SSDP_V6_ADDRESSES.len());
+
+    for group in SSDP_V6_ADDRESSES {
+        socket
+            .join_multicast_v6(&group, iface_index)
+            .with_context(|| format!("Failed to join SSDP multicast group {}.", group))?;
+
+        groups.push(SocketAddrV6::new(group, SSDP_PORT, 0, iface_index).into());
     }
+
+    Ok(SSDPSocket {
+        socket,
+        groups,
+        iface_addrs: iface.map(|iface| iface.addrs.clone()).unwrap_or_default(),
+    })
 }
 
-async fn ssdp_socket(broadcast_iface: Option<String>) -> Result<Arc<UdpSocket>> {
-    let ssdp1 = UdpSocket::bind(DUMMY_ADDRESS)
-        .await
-        .context("Failed to bind SSDP socket")?;
+/// SO_REUSEADDR only lets us share the SSDP port with other stacks if set before binding, hence the raw socket.
+pub fn bind_ssdp_socket(bind_addr: SocketAddr, broadcast_iface: Option<&str>) -> Result<UdpSocket> {
+    let family = match bind_addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+
+    let fd = socket::socket(family, SockType::Datagram, SockFlag::empty(), None)
+        .context("Failed to create SSDP socket.")?;
 
-    socket::setsockopt(&ssdp1.as_fd(), ReuseAddr, &true).context("Failed to set SO_REUSEADDR.")?;
+    socket::setsockopt(&fd, ReuseAddr, &true).context("Failed to set SO_REUSEADDR.")?;
+
+    //Leave the IPv4 side of the port to the IPv4 socket.
+    if bind_addr.is_ipv6() {
+        socket::setsockopt(&fd, Ipv6V6Only, &true).context("Failed to set IPV6_V6ONLY.")?;
+    }
 
     if let Some(_iface) = broadcast_iface {
         #[cfg(any(target_os = "android", target_os = "linux"))]
         {
             let iface: std::ffi::OsString = std::ffi::OsString::from(_iface);
 
-            socket::setsockopt(&ssdp1.as_fd(), BindToDevice, &iface)
+            socket::setsockopt(&fd, BindToDevice, &iface)
                 .context("Failed to set SO_BINDTODEVICE.")?;
         }
 
@@ -92,30 +340,64 @@ async fn ssdp_socket(broadcast_iface: Option<String>) -> Result<Arc<UdpSocket>>
         panic!("Cannot set broadcast address on MacOS (yet)")
     }
 
-    ssdp1
-        .join_multicast_v4(SSDP_ADDRESS.0, Ipv4Addr::UNSPECIFIED)
-        .context("Failed to join SSDP multicast group.")?;
+    socket::bind(fd.as_raw_fd(), &SockaddrStorage::from(bind_addr))
+        .with_context(|| format!("Failed to bind SSDP socket on {}.", bind_addr))?;
 
-    Ok(Arc::new(ssdp1))
+    let socket = std::net::UdpSocket::from(fd);
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket).context("Failed to register SSDP socket.")
 }
 
-pub async fn main_task(ssdp: SSDPManager) -> Result<()> {
+pub async fn main_task(ssdp: SSDPManager, shutdown: CancellationToken) -> Result<()> {
     info!(target: "dlnaproxy", "Launched main task...");
 
     //We send an initial byebye before all else because... that's how MiniDLNA does it.
     //Guessing that it's for clearing any cache that might exist on listening remote devices.
-    ssdp.interactive_ssdp
-        .send_byebye(&ssdp.socket, SSDP_ADDRESS)
-        .await
-        .context("Failed to send initial ssdp:byebye !")?;
+    //The remote server might not be up yet, the broadcast task will keep trying to reach it.
+    for endpoint in &ssdp.endpoints {
+        if let Err(msg) = endpoint.interactive_ssdp.send_byebye(&ssdp.sockets).await {
+            warn!(target: "dlnaproxy", "Failed to send initial ssdp:byebye: {}", msg);
+        }
+    }
+
+    let helpers: Arc<[_]> = ssdp
+        .endpoints
+        .iter()
+        .map(|endpoint| endpoint.interactive_ssdp.clone())
+        .collect();
+
+    let broadcast_handles: Vec<_> = ssdp
+        .endpoints
+        .into_iter()
+        .map(|endpoint| match endpoint.follower {
+            Some(follower) => tokio::task::spawn(follow_task(follower, shutdown.clone())),
+            None => tokio::task::spawn(broadcast_task(
+                endpoint.broadcaster,
+                endpoint.broadcast_period,
+                shutdown.clone(),
+            )),
+        })
+        .collect();
 
-    let _broadcast_handle =
-        tokio::task::spawn(broadcast_task(ssdp.broadcaster, ssdp.broadcast_period));
+    let listener_handles: Vec<_> = ssdp
+        .sockets
+        .into_iter()
+        .map(|socket| {
+            tokio::task::spawn(listen_task(socket, Arc::clone(&helpers), shutdown.clone()))
+        })
+        .collect();
 
-    let _listener_handle =
-        tokio::task::spawn(listen_task(ssdp.socket, ssdp.interactive_ssdp.clone()));
+    for handle in listener_handles {
+        let _ = handle.await;
+    }
+
+    //Broadcast tasks are done once they have said goodbye on behalf of their server.
+    for handle in broadcast_handles {
+        let _ = handle.await;
+    }
 
-    let _ = _listener_handle.await;
+    info!(target: "dlnaproxy", "SSDP tasks stopped.");
 
     Ok(())
 }
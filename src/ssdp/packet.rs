@@ -1,32 +1,42 @@
 use chrono::Utc;
 use std::fmt;
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
 
 use anyhow::{Context, Result};
 
 pub enum SSDPPacket {
     Alive {
+        /// Multicast group the notification is sent to.
+        host: SocketAddr,
         desc_url: String,
         server_ua: String,
-        unique_device_name: String,
-        device_type: String,
+        notification_type: String,
+        unique_service_name: String,
         cache_max_age: usize,
     },
     Ok {
         desc_url: String,
         server_ua: String,
-        unique_device_name: String,
-        device_type: String,
+        search_target: String,
+        unique_service_name: String,
         cache_max_age: usize,
     },
     ByeBye {
-        unique_device_name: String,
-        device_type: String,
+        host: SocketAddr,
+        notification_type: String,
+        unique_service_name: String,
+    },
+    MSearch {
+        host: SocketAddr,
+        search_target: String,
+        /// Seconds devices may wait before answering, none for unicast searches, which are answered right away.
+        max_wait: Option<u64>,
     },
 }
 
 impl SSDPPacket {
-    pub async fn send_to(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
+    pub async fn send_to(&self, socket: &UdpSocket, dest: SocketAddr) -> Result<()> {
         socket
             .send_to(self.to_string().as_bytes(), dest)
             .await
@@ -40,37 +50,39 @@ impl fmt::Display for SSDPPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SSDPPacket::Alive {
+                host,
                 desc_url,
                 server_ua,
-                unique_device_name,
-                device_type,
+                notification_type,
+                unique_service_name,
                 cache_max_age,
             } => {
                 write!(
                     f,
                     "\
 NOTIFY * HTTP/1.1\r\n\
-HOST:239.255.255.250:1900\r\n\
+HOST:{host}\r\n\
 CACHE-CONTROL:max-age={cache_max_age}\r\n\
 LOCATION:{location}\r\n\
 SERVER: {server_ua}\r\n\
-NT:{device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
+NT:{notification_type}\r\n\
+USN:{usn}\r\n\
 NTS:ssdp:alive\r\n\
 \r\n",
+                    host = host_header(host),
                     cache_max_age = cache_max_age,
                     location = desc_url,
                     server_ua = server_ua,
-                    device_type = device_type,
-                    udn = unique_device_name
+                    notification_type = notification_type,
+                    usn = unique_service_name
                 )
             }
 
             SSDPPacket::Ok {
                 desc_url,
                 server_ua,
-                unique_device_name,
-                device_type,
+                search_target,
+                unique_service_name,
                 cache_max_age,
             } => {
                 let now = Utc::now().to_rfc2822().replace("+0000", "GMT");
@@ -81,8 +93,8 @@ NTS:ssdp:alive\r\n\
 HTTP/1.1 200 OK\r\n\
 CACHE-CONTROL:max-age={cache_max_age}\r\n\
 DATE: {date}\r\n\
-ST: {device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
+ST: {search_target}\r\n\
+USN:{usn}\r\n\
 EXT:\r\n\
 SERVER: {server_ua}\r\n\
 LOCATION:{location}\r\n\
@@ -91,29 +103,62 @@ Content-Length: 0\r\n\
                     cache_max_age = cache_max_age,
                     location = desc_url,
                     server_ua = server_ua,
-                    device_type = device_type,
-                    udn = unique_device_name,
+                    search_target = search_target,
+                    usn = unique_service_name,
                     date = now
                 )
             }
 
             SSDPPacket::ByeBye {
-                unique_device_name,
-                device_type,
+                host,
+                notification_type,
+                unique_service_name,
             } => {
                 write!(
                     f,
                     "\
 NOTIFY * HTTP/1.1\r\n\
-HOST:239.255.255.250:1900\r\n\
-NT:{device_type}\r\n\
-USN:{udn}::{device_type}\r\n\
+HOST:{host}\r\n\
+NT:{notification_type}\r\n\
+USN:{usn}\r\n\
 NTS:ssdp:byebye\r\n\
 \r\n",
-                    device_type = device_type,
-                    udn = unique_device_name
+                    host = host_header(host),
+                    notification_type = notification_type,
+                    usn = unique_service_name
                 )
             }
+
+            SSDPPacket::MSearch {
+                host,
+                search_target,
+                max_wait,
+            } => {
+                write!(
+                    f,
+                    "\
+M-SEARCH * HTTP/1.1\r\n\
+HOST:{host}\r\n\
+MAN:\"ssdp:discover\"\r\n",
+                    host = host_header(host)
+                )?;
+
+                if let Some(max_wait) = max_wait {
+                    write!(f, "MX:{}\r\n", max_wait)?;
+                }
+
+                write!(f, "ST:{}\r\n\r\n", search_target)
+            }
+        }
+    }
+}
+
+/// UDA 2.0 spells the IPv6 groups `[FF02::C]:1900`, without the scope of the destination address.
+fn host_header(host: &SocketAddr) -> String {
+    match host {
+        SocketAddr::V4(host) => host.to_string(),
+        SocketAddr::V6(host) => {
+            format!("[{}]:{}", host.ip().to_string().to_uppercase(), host.port())
         }
     }
 }
@@ -0,0 +1,334 @@
+use log::{debug, trace, warn};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use httparse::{Response, EMPTY_HEADER};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time;
+
+use super::interfaces::Interfaces;
+use super::packet::SSDPPacket;
+use super::{bind_ssdp_socket, SSDP_ADDRESS, SSDP_PORT};
+
+/// Seconds devices may wait before answering our searches.
+const SEARCH_MX: u64 = 3;
+
+/// How long we wait for answers to unicast searches, which come right away but may cross a VPN.
+const UNICAST_WAIT: Duration = Duration::from_secs(2);
+
+/// Largest subnet searched with unicast M-SEARCHes, one per address.
+const MIN_SUBNET_PREFIX: u8 = 22;
+
+/// Where unicast searches are sent, for networks multicast doesn't reach.
+#[derive(Clone, Debug)]
+pub enum SearchHosts {
+    /// A host name or address.
+    Host(String),
+    /// Every address of an IPv4 subnet, e.g. `10.8.0.0/24`.
+    Subnet(Ipv4Addr, u8),
+}
+
+impl FromStr for SearchHosts {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((network, prefix)) = s.split_once('/') else {
+            return Ok(SearchHosts::Host(s.to_string()));
+        };
+
+        let network: Ipv4Addr = network
+            .parse()
+            .with_context(|| format!("Bad subnet '{}'.", s))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= 32)
+            .with_context(|| format!("Bad subnet '{}'.", s))?;
+
+        if prefix < MIN_SUBNET_PREFIX {
+            return Err(anyhow!(
+                "Subnet '{}' is too large, /{} at most.",
+                s,
+                MIN_SUBNET_PREFIX
+            ));
+        }
+
+        Ok(SearchHosts::Subnet(network, prefix))
+    }
+}
+
+impl fmt::Display for SearchHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchHosts::Host(host) => f.write_str(host),
+            SearchHosts::Subnet(network, prefix) => write!(f, "{}/{}", network, prefix),
+        }
+    }
+}
+
+impl SearchHosts {
+    async fn addrs(&self) -> Result<Vec<SocketAddr>> {
+        match self {
+            SearchHosts::Host(host) => Ok(tokio::net::lookup_host((host.as_str(), SSDP_PORT))
+                .await
+                .with_context(|| format!("Failed to resolve '{}'.", host))?
+                .collect()),
+            SearchHosts::Subnet(network, prefix) => {
+                let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                let first = u32::from(*network) & mask;
+                let last = first | !mask;
+
+                //Network and broadcast addresses aside, but for /31 and /32 which have none.
+                let hosts = match prefix {
+                    31 | 32 => first..=last,
+                    _ => first + 1..=last - 1,
+                };
+
+                Ok(hosts
+                    .map(|host| (Ipv4Addr::from(host), SSDP_PORT).into())
+                    .collect())
+            }
+        }
+    }
+}
+
+/// A device that answered one of our searches.
+#[derive(Debug)]
+pub struct Found {
+    /// UDN of the device, e.g. `uuid:...`.
+    pub udn: String,
+    pub location: Url,
+}
+
+/// Looks for devices matching `search_target` on every interface, over IPv4.
+pub async fn search(interfaces: &Interfaces, search_target: &str) -> Result<Vec<Found>> {
+    let names: Vec<Option<String>> = match interfaces.resolve()? {
+        None => vec![None],
+        Some(interfaces) => interfaces
+            .into_iter()
+            .map(|iface| Some(iface.name))
+            .collect(),
+    };
+
+    let mut searches = JoinSet::new();
+
+    for name in names {
+        let search_target = search_target.to_string();
+
+        searches.spawn(async move {
+            let found = search_on(name.as_deref(), &search_target).await;
+            (name, found)
+        });
+    }
+
+    let mut udns = HashSet::new();
+    let mut found = Vec::new();
+
+    while let Some(result) = searches.join_next().await {
+        let (name, result) = result.context("Search task failed.")?;
+
+        match result {
+            Ok(devices) => found.extend(
+                devices
+                    .into_iter()
+                    .filter(|device| udns.insert(device.udn.clone())),
+            ),
+            Err(e) => {
+                warn!(target: "dlnaproxy", "Search on {} failed: {:#}", name.as_deref().unwrap_or("the default interface"), e)
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Looks for devices matching `search_target` with unicast M-SEARCHes (UDA 1.1) to `hosts`.
+pub async fn search_unicast(hosts: &SearchHosts, search_target: &str) -> Result<Vec<Found>> {
+    let addrs = hosts.addrs().await?;
+
+    let unspecified: SocketAddr = match addrs.first() {
+        None => return Err(anyhow!("'{}' has no address.", hosts)),
+        Some(SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        Some(SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(unspecified)
+        .await
+        .context("Failed to bind search socket.")?;
+
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| addr.is_ipv4() == unspecified.is_ipv4())
+        .collect();
+
+    for addr in &addrs {
+        let search = SSDPPacket::MSearch {
+            host: *addr,
+            search_target: search_target.into(),
+            max_wait: None,
+        };
+
+        //Some addresses of a subnet may well be unreachable.
+        if let Err(e) = search.send_to(&socket, *addr).await {
+            debug!(target: "dlnaproxy", "Failed to search {}: {:#}", addr, e);
+        }
+    }
+
+    let hosts: HashSet<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+
+    let mut udns = HashSet::new();
+    let mut found = Vec::new();
+    let mut buffer = [0; 2048];
+
+    let deadline = time::Instant::now() + UNICAST_WAIT;
+
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (length, sender) = received.context("Failed to receive search responses.")?;
+
+        if !hosts.contains(&sender.ip()) {
+            continue;
+        }
+
+        match parse_response(&buffer[..length], sender) {
+            Ok(device) if udns.insert(device.udn.clone()) => {
+                trace!(target: "dlnaproxy", "{} answered our search: {:?}", sender, device);
+                found.push(device);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Ignoring search response from {}: {:#}", sender, e)
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+async fn search_on(iface: Option<&str>, search_target: &str) -> Result<Vec<Found>> {
+    //Bound to the interface, so that the search goes out there and not wherever the system routes multicast.
+    let socket = bind_ssdp_socket((Ipv4Addr::UNSPECIFIED, 0).into(), iface)?;
+
+    let search = SSDPPacket::MSearch {
+        host: SSDP_ADDRESS.into(),
+        search_target: search_target.into(),
+        max_wait: Some(SEARCH_MX),
+    };
+
+    search.send_to(&socket, SSDP_ADDRESS.into()).await?;
+
+    let mut found = Vec::new();
+    let mut buffer = [0; 2048];
+
+    let deadline = time::Instant::now() + Duration::from_secs(SEARCH_MX + 1);
+
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (length, sender) = received.context("Failed to receive search responses.")?;
+
+        match parse_response(&buffer[..length], sender) {
+            Ok(device) => {
+                trace!(target: "dlnaproxy", "{} answered our search: {:?}", sender, device);
+                found.push(device);
+            }
+            Err(e) => {
+                debug!(target: "dlnaproxy", "Ignoring search response from {}: {:#}", sender, e)
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+fn parse_response(packet: &[u8], sender: SocketAddr) -> Result<Found> {
+    let mut headers = [EMPTY_HEADER; 16];
+    let mut response = Response::new(&mut headers);
+
+    response
+        .parse(packet)
+        .context("Failed to parse search response.")?;
+
+    if response.code != Some(200) {
+        return Err(anyhow!("Unexpected status {:?}.", response.code));
+    }
+
+    let header = |name: &str| {
+        response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).trim().to_string())
+    };
+
+    let location = header("LOCATION").context("Missing LOCATION header.")?;
+    let usn = header("USN").context("Missing USN header.")?;
+
+    let location =
+        Url::parse(&location).with_context(|| format!("Bad LOCATION '{}'.", location))?;
+
+    if location.host_str().is_none() {
+        return Err(anyhow!(
+            "LOCATION '{}' from {} has no host.",
+            location,
+            sender
+        ));
+    }
+
+    let udn = usn.split("::").next().unwrap_or_default().to_string();
+
+    Ok(Found { udn, location })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn addrs(hosts: &str) -> Vec<SocketAddr> {
+        hosts.parse::<SearchHosts>().unwrap().addrs().await.unwrap()
+    }
+
+    fn addr(ip: [u8; 4]) -> SocketAddr {
+        (Ipv4Addr::from(ip), SSDP_PORT).into()
+    }
+
+    #[tokio::test]
+    async fn subnet_addrs() {
+        //Network and broadcast addresses aside.
+        let hosts = addrs("10.8.0.0/24").await;
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts.first(), Some(&addr([10, 8, 0, 1])));
+        assert_eq!(hosts.last(), Some(&addr([10, 8, 0, 254])));
+
+        //Point-to-point links have neither.
+        assert_eq!(
+            addrs("10.8.0.4/31").await,
+            [addr([10, 8, 0, 4]), addr([10, 8, 0, 5])]
+        );
+        assert_eq!(addrs("10.8.0.5/32").await, [addr([10, 8, 0, 5])]);
+
+        //A host address stands for its network.
+        assert_eq!(addrs("10.8.0.77/24").await, addrs("10.8.0.0/24").await);
+        assert_eq!(
+            addrs("10.8.5.9/22").await.first(),
+            Some(&addr([10, 8, 4, 1]))
+        );
+    }
+
+    #[test]
+    fn large_or_bad_subnets_are_refused() {
+        assert!("10.8.0.0/22".parse::<SearchHosts>().is_ok());
+        assert!("10.8.0.0/21".parse::<SearchHosts>().is_err());
+        assert!("10.8.0.0/33".parse::<SearchHosts>().is_err());
+        assert!("10.8.0/24".parse::<SearchHosts>().is_err());
+    }
+
+    #[tokio::test]
+    async fn host_addrs() {
+        assert_eq!(addrs("127.0.0.1").await, [addr([127, 0, 0, 1])]);
+    }
+}
@@ -1,13 +1,39 @@
-use log::{debug, trace};
-use tokio::net::ToSocketAddrs;
+use log::{debug, trace, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::UdpSocket;
 
 use anyhow::Context;
 use anyhow::Result;
-use reqwest::header::SERVER;
+use reqwest::Url;
 use serde::Deserialize;
 
+use crate::ssdp::cache::EndpointCache;
 use crate::ssdp::packet::SSDPPacket;
+use crate::ssdp::SSDPSocket;
+
+#[derive(Debug, Deserialize)]
+struct DLNAService {
+    #[serde(rename = "serviceType")]
+    service_type: String,
+
+    #[serde(rename = "controlURL", default)]
+    control_url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DLNAServiceList {
+    #[serde(default)]
+    service: Vec<DLNAService>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DLNADeviceList {
+    #[serde(default)]
+    device: Vec<DLNADevice>,
+}
 
 #[derive(Debug, Deserialize)]
 struct DLNADevice {
@@ -16,73 +42,300 @@ struct DLNADevice {
 
     #[serde(rename = "UDN")]
     unique_device_name: String,
+
+    #[serde(rename = "friendlyName", default)]
+    friendly_name: String,
+
+    #[serde(rename = "serviceList", default)]
+    service_list: DLNAServiceList,
+
+    #[serde(rename = "deviceList", default)]
+    device_list: DLNADeviceList,
 }
 
 #[derive(Debug, Deserialize)]
 struct DLNADescription {
+    #[serde(rename = "URLBase")]
+    url_base: Option<String>,
+
     device: DLNADevice,
 }
 
-pub struct EndpointInfo {
+/// A device (root or embedded) as described by the remote server.
+pub struct DeviceInfo {
     pub device_type: String,
     pub unique_device_name: String,
+    pub friendly_name: String,
+    pub services: Vec<ServiceInfo>,
+}
+
+pub struct ServiceInfo {
+    pub service_type: String,
+    /// As found in the description, possibly relative to the URL base.
+    pub control_url: String,
+}
+
+/// A NT/USN pair, each of them being announced in its own NOTIFY.
+pub struct Advertisement {
+    pub notification_type: String,
+    pub unique_service_name: String,
+}
+
+pub struct EndpointInfo {
+    /// Root device first, then embedded devices in document order.
+    pub devices: Vec<DeviceInfo>,
+    pub url_base: Option<String>,
     pub server: String,
 }
 
+impl DLNADevice {
+    fn flatten_into(self, devices: &mut Vec<DeviceInfo>) {
+        let mut services: Vec<ServiceInfo> = Vec::with_capacity(self.service_list.service.len());
+
+        for service in self.service_list.service {
+            if !services
+                .iter()
+                .any(|known| known.service_type == service.service_type)
+            {
+                services.push(ServiceInfo {
+                    service_type: service.service_type,
+                    control_url: service.control_url,
+                });
+            }
+        }
+
+        devices.push(DeviceInfo {
+            device_type: self.device_type,
+            unique_device_name: self.unique_device_name,
+            friendly_name: self.friendly_name,
+            services,
+        });
+
+        for embedded in self.device_list.device {
+            embedded.flatten_into(devices);
+        }
+    }
+}
+
+impl DeviceInfo {
+    fn types(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.device_type).chain(self.services.iter().map(|s| &s.service_type))
+    }
+}
+
+impl EndpointInfo {
+    pub fn root_device(&self) -> &DeviceInfo {
+        &self.devices[0]
+    }
+
+    /// First service of type `service` (e.g. `ContentDirectory`), any version, along with its device.
+    pub fn service(&self, service: &str) -> Option<(&DeviceInfo, &ServiceInfo)> {
+        self.devices.iter().find_map(|device| {
+            device
+                .services
+                .iter()
+                .find(|s| s.service_type.rsplit(':').nth(1) == Some(service))
+                .map(|s| (device, s))
+        })
+    }
+
+    /// The complete set of advertisements required by the UPnP Device Architecture:
+    /// upnp:rootdevice once, then uuid, device type and service types for every device.
+    pub fn advertisements(&self) -> Vec<Advertisement> {
+        let root_udn = &self.root_device().unique_device_name;
+
+        let mut advertisements = vec![Advertisement {
+            notification_type: "upnp:rootdevice".into(),
+            unique_service_name: format!("{}::upnp:rootdevice", root_udn),
+        }];
+
+        for device in &self.devices {
+            let udn = &device.unique_device_name;
+
+            advertisements.push(Advertisement {
+                notification_type: udn.clone(),
+                unique_service_name: udn.clone(),
+            });
+
+            advertisements.extend(device.types().map(|nt| Advertisement {
+                notification_type: nt.clone(),
+                unique_service_name: format!("{}::{}", udn, nt),
+            }));
+        }
+
+        advertisements
+    }
+
+    /// Advertisements answering a M-SEARCH for `search_target`, with their NT set to the ST to
+    /// respond with. Device and service types follow the UPnP versioning rules: we respond to
+    /// a search for any version up to the one we advertise, echoing the version searched for.
+    pub fn search_responses(&self, search_target: &str) -> Vec<Advertisement> {
+        match search_target {
+            "ssdp:all" => self.advertisements(),
+            "upnp:rootdevice" => self
+                .advertisements()
+                .into_iter()
+                .filter(|adv| adv.notification_type == search_target)
+                .collect(),
+            target if target.starts_with("uuid:") => self
+                .devices
+                .iter()
+                .filter(|device| device.unique_device_name == target)
+                .map(|device| Advertisement {
+                    notification_type: device.unique_device_name.clone(),
+                    unique_service_name: device.unique_device_name.clone(),
+                })
+                .collect(),
+            target => {
+                let Some((searched_type, searched_version)) = split_type_version(target) else {
+                    return Vec::new();
+                };
+
+                let mut responses = Vec::new();
+
+                for device in &self.devices {
+                    let matches = device.types().filter_map(|t| split_type_version(t)).any(
+                        |(advertised_type, advertised_version)| {
+                            advertised_type == searched_type
+                                && advertised_version >= searched_version
+                        },
+                    );
+
+                    if matches {
+                        responses.push(Advertisement {
+                            notification_type: target.into(),
+                            unique_service_name: format!(
+                                "{}::{}",
+                                device.unique_device_name, target
+                            ),
+                        });
+                    }
+                }
+
+                responses
+            }
+        }
+    }
+}
+
+/// Splits `urn:domain:device:Type:2` into `("urn:domain:device:Type", 2)`.
+fn split_type_version(urn: &str) -> Option<(&str, u32)> {
+    if !urn.starts_with("urn:") {
+        return None;
+    }
+
+    let (urn_type, version) = urn.rsplit_once(':')?;
+
+    version.parse().ok().map(|version| (urn_type, version))
+}
+
+pub fn parse_endpoint_info(body: &str, server: String) -> Result<EndpointInfo> {
+    let device_description: DLNADescription =
+        quick_xml::de::from_str(body).context("Failed to parse device's XML description.")?;
+
+    let mut devices = Vec::new();
+    device_description.device.flatten_into(&mut devices);
+
+    Ok(EndpointInfo {
+        devices,
+        url_base: device_description.url_base,
+        server,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemoteState {
+    /// We haven't heard from the remote server yet.
+    Unknown,
+    Available,
+    Unreachable,
+}
+
 pub struct InteractiveSSDP {
-    http_client: reqwest::Client,
-    remote_desc_url: String,
-    cache_max_age: usize,
+    endpoint_cache: Arc<EndpointCache>,
+    state: Mutex<RemoteState>,
+    /// Advertised LOCATION.
+    location: Url,
+    /// Wildcard address the proxy in LOCATION listens on, replaced per socket by a reachable one.
+    wildcard_host: Option<IpAddr>,
+    /// CACHE-CONTROL max-age of our announcements and responses, in seconds.
+    cache_max_age: AtomicUsize,
 }
 
 impl InteractiveSSDP {
-    pub fn new(client: reqwest::Client, url: &str, cache_max_age: usize) -> Self {
+    pub fn new(
+        client: reqwest::Client,
+        url: &Url,
+        cache_max_age: usize,
+        description_ttl: Duration,
+    ) -> Self {
+        let wildcard_host = url
+            .host_str()
+            .and_then(|host| host.trim_matches(['[', ']']).parse::<IpAddr>().ok())
+            .filter(IpAddr::is_unspecified);
+
+        //We reach a proxy listening on every address through loopback.
+        let mut fetch_url = url.clone();
+
+        if let Some(bound) = wildcard_host {
+            let loopback: IpAddr = match bound {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            };
+
+            let _ = fetch_url.set_ip_host(loopback);
+        }
+
         InteractiveSSDP {
-            http_client: client,
-            remote_desc_url: url.into(),
-            cache_max_age,
+            endpoint_cache: Arc::new(EndpointCache::new(
+                client,
+                fetch_url.as_str(),
+                description_ttl,
+            )),
+            state: Mutex::new(RemoteState::Unknown),
+            location: url.clone(),
+            wildcard_host,
+            cache_max_age: AtomicUsize::new(cache_max_age),
         }
     }
 
-    async fn fetch_endpoint_info(&self) -> Result<EndpointInfo> {
-        trace!(target: "dlnaproxy", "Fetching remote server's info.");
-
-        let endpoint_response = self
-            .http_client
-            .get(&self.remote_desc_url)
-            .send()
-            .await
-            .context("Failed to get description of remote endpoint.")?;
-
-        let server_ua = endpoint_response
-            .headers()
-            .get(SERVER)
-            .map(|hv| String::from_utf8_lossy(hv.as_bytes()).to_string())
-            .unwrap_or_else(|| "DLNAProxy/1.0".into());
+    pub fn state(&self) -> RemoteState {
+        *self.state.lock().unwrap()
+    }
 
-        let body = endpoint_response
-            .text()
-            .await
-            .context("Failed to parse response's body as text.")?;
+    /// LOCATION to advertise on `socket` to `dest`, a multicast group or a searcher.
+    fn location_for(&self, socket: &SSDPSocket, dest: SocketAddr) -> String {
+        match self
+            .wildcard_host
+            .and_then(|bound| socket.location_host(bound, dest))
+        {
+            Some(host) => {
+                let mut location = self.location.clone();
+                let _ = location.set_ip_host(host);
+                location.into()
+            }
+            None => self.location.to_string(),
+        }
+    }
 
-        let device_description: DLNADescription =
-            quick_xml::de::from_str(&body).context("Failed to parse device's XML description.")?;
+    /// Servers we follow tell how long their announcements hold, ours should hold as long.
+    pub fn set_cache_max_age(&self, max_age: usize) {
+        self.cache_max_age.store(max_age, Ordering::Relaxed);
+    }
 
-        Ok(EndpointInfo {
-            device_type: device_description.device.device_type,
-            unique_device_name: device_description.device.unique_device_name,
-            server: server_ua,
-        })
+    /// Moves to `new_state`, returning the previous one.
+    pub fn transition(&self, new_state: RemoteState) -> RemoteState {
+        std::mem::replace(&mut self.state.lock().unwrap(), new_state)
     }
 
     async fn send_to(
         &self,
         socket: &UdpSocket,
-        dest: impl ToSocketAddrs,
+        dest: SocketAddr,
         ssdp_packet: SSDPPacket,
         p_type: &str,
     ) -> Result<()> {
-        trace!(target: "dlnaproxy", "{}", ssdp_packet.to_string());
+        trace!(target: "dlnaproxy", "{}", ssdp_packet);
 
         ssdp_packet.send_to(socket, dest).await?;
 
@@ -90,42 +343,121 @@ impl InteractiveSSDP {
         Ok(())
     }
 
-    pub async fn send_alive(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+    /// Periodic announcements are also what keeps the cached description up to date.
+    pub async fn send_alive(&self, sockets: &[Arc<SSDPSocket>]) -> Result<()> {
+        let info = self.endpoint_cache.refresh().await?;
 
-        let ssdp_alive = SSDPPacket::Alive {
-            desc_url: self.remote_desc_url.clone(),
-            server_ua: info.server,
-            device_type: info.device_type,
-            unique_device_name: info.unique_device_name,
-            cache_max_age: self.cache_max_age,
-        };
+        for socket in sockets {
+            for &group in &socket.groups {
+                let location = self.location_for(socket, group);
 
-        self.send_to(socket, dest, ssdp_alive, "alive").await
-    }
+                let packets =
+                    info.advertisements()
+                        .into_iter()
+                        .map(|advertisement| SSDPPacket::Alive {
+                            host: group,
+                            desc_url: location.clone(),
+                            server_ua: info.server.clone(),
+                            notification_type: advertisement.notification_type,
+                            unique_service_name: advertisement.unique_service_name,
+                            cache_max_age: self.cache_max_age.load(Ordering::Relaxed),
+                        });
 
-    pub async fn send_ok(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+                self.multicast(&socket.socket, group, packets, "alive")
+                    .await;
+            }
+        }
 
-        let ssdp_ok = SSDPPacket::Ok {
-            desc_url: self.remote_desc_url.clone(),
-            unique_device_name: info.unique_device_name,
-            device_type: info.device_type,
-            server_ua: info.server,
-            cache_max_age: self.cache_max_age,
-        };
+        Ok(())
+    }
 
-        self.send_to(socket, dest, ssdp_ok, "ok").await
+    /// Whether a M-SEARCH for `search_target` gets a response, as far as the cached description tells.
+    pub fn answers(&self, search_target: &str) -> bool {
+        self.state() == RemoteState::Available
+            && self
+                .endpoint_cache
+                .cached()
+                .is_some_and(|info| !info.search_responses(search_target).is_empty())
     }
 
-    pub async fn send_byebye(&self, socket: &UdpSocket, dest: impl ToSocketAddrs) -> Result<()> {
-        let info = self.fetch_endpoint_info().await?;
+    /// Answers a M-SEARCH with one response per advertisement matching the search target.
+    /// Returns how many responses were sent.
+    pub async fn send_ok(
+        &self,
+        socket: &SSDPSocket,
+        dest: SocketAddr,
+        search_target: &str,
+    ) -> Result<usize> {
+        if self.state() != RemoteState::Available {
+            trace!(target: "dlnaproxy", "Remote server isn't available, ignoring M-SEARCH.");
+            return Ok(0);
+        }
+
+        let info = self.endpoint_cache.get().await?;
+
+        let responses = info.search_responses(search_target);
+
+        if responses.is_empty() {
+            trace!(target: "dlnaproxy", "'{}' doesn't match anything advertised by '{}', ignoring.", search_target, info.root_device().unique_device_name);
+            return Ok(0);
+        }
+
+        let location = self.location_for(socket, dest);
+
+        for response in &responses {
+            let ssdp_ok = SSDPPacket::Ok {
+                desc_url: location.clone(),
+                search_target: response.notification_type.clone(),
+                unique_service_name: response.unique_service_name.clone(),
+                server_ua: info.server.clone(),
+                cache_max_age: self.cache_max_age.load(Ordering::Relaxed),
+            };
+
+            self.send_to(&socket.socket, dest, ssdp_ok, "ok").await?;
+        }
 
-        let ssdp_byebye = SSDPPacket::ByeBye {
-            unique_device_name: info.unique_device_name,
-            device_type: info.device_type,
+        Ok(responses.len())
+    }
+
+    /// Prefers whatever info was cached, as the remote server may well be gone already.
+    pub async fn send_byebye(&self, sockets: &[Arc<SSDPSocket>]) -> Result<()> {
+        let info = match self.endpoint_cache.cached() {
+            Some(info) => info,
+            None => self.endpoint_cache.get().await?,
         };
 
-        self.send_to(socket, dest, ssdp_byebye, "byebye").await
+        for socket in sockets {
+            for &group in &socket.groups {
+                let packets =
+                    info.advertisements()
+                        .into_iter()
+                        .map(|advertisement| SSDPPacket::ByeBye {
+                            host: group,
+                            notification_type: advertisement.notification_type,
+                            unique_service_name: advertisement.unique_service_name,
+                        });
+
+                self.multicast(&socket.socket, group, packets, "byebye")
+                    .await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A group we can't send to (e.g. no IPv6 route) shouldn't keep the others from being notified.
+    async fn multicast(
+        &self,
+        socket: &UdpSocket,
+        group: SocketAddr,
+        packets: impl Iterator<Item = SSDPPacket>,
+        p_type: &str,
+    ) {
+        for packet in packets {
+            if let Err(msg) = self.send_to(socket, group, packet, p_type).await {
+                warn!(target: "dlnaproxy", "Couldn't send ssdp:{} to {}: {:#}", p_type, group, msg);
+                return;
+            }
+        }
     }
 }
@@ -1,68 +1,110 @@
 use log::{debug, info, trace, warn};
 
-use std::{
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::{
     io,
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::Arc,
-    thread::{self, JoinHandle},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
 };
+use tokio_util::sync::CancellationToken;
+
+use crate::http::{self, HttpContext};
 
-//Adapted from https://github.com/hishboy/rust-tcp-proxy/
+pub struct TCPProxy {
+    /// Set when HTTP traffic is inspected and rewritten, raw TCP piping otherwise.
+    http_context: Option<Arc<HttpContext>>,
+}
 
-pub struct TCPProxy;
 impl TCPProxy {
-    pub fn start(self, to: SocketAddr, from: SocketAddr) -> JoinHandle<()> {
-        let listener = TcpListener::bind(from).expect("Unable to bind proxy addr");
+    pub fn raw() -> Self {
+        TCPProxy { http_context: None }
+    }
+
+    pub fn http(context: HttpContext) -> Self {
+        TCPProxy {
+            http_context: Some(Arc::new(context)),
+        }
+    }
+
+    /// Binds `from` and relays every connection to `to` until `shutdown` is triggered.
+    pub async fn start(
+        self,
+        to: SocketAddr,
+        from: SocketAddr,
+        shutdown: CancellationToken,
+    ) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(from)
+            .await
+            .with_context(|| format!("Unable to bind proxy address {}", from))?;
 
         info!(target: "dlnaproxy", "Proxing TCP connections from {} to {}.", from, to);
 
-        thread::spawn(self.listen_loop(listener, to))
+        Ok(tokio::spawn(self.listen_loop(listener, to, shutdown)))
     }
 
-    fn listen_loop(&self, listener: TcpListener, origin: SocketAddr) -> impl FnOnce() {
-        move || {
-            for incoming_stream in listener.incoming() {
-                let proxied_stream = if let Ok(stream) = incoming_stream {
-                    stream
-                } else {
-                    continue;
-                };
+    async fn listen_loop(
+        self,
+        listener: TcpListener,
+        origin: SocketAddr,
+        shutdown: CancellationToken,
+    ) {
+        let Ok(bound) = listener.local_addr() else {
+            return;
+        };
 
-                let peer_addr = proxied_stream.peer_addr().unwrap();
+        loop {
+            let (proxied_stream, peer_addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!(target: "dlnaproxy", "Failed to accept proxy connection: {}", err);
+                        continue;
+                    }
+                },
+            };
 
-                let conn_thread = TcpStream::connect(origin)
-                    .map(|to_stream| thread::spawn(move || handle_conn(proxied_stream, to_stream)));
+            debug!(target: "dlnaproxy", "Accepted a connection from client: {}", peer_addr);
 
-                match conn_thread {
-                    Ok(_) => {
-                        debug!(target: "dlnaproxy", "Successfully established a connection with client: {}", peer_addr);
+            let http_context = self.http_context.clone();
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                let proxying = async {
+                    match http_context {
+                        Some(context) => {
+                            http::handle_conn(proxied_stream, origin, bound, &context).await
+                        }
+                        None => handle_conn(proxied_stream, origin).await,
                     }
-                    Err(err) => {
-                        warn!(target: "dlnaproxy", "Unable to establish a connection with client: {}", err);
+                };
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        trace!(target: "dlnaproxy", "Dropping connection with {} on shutdown.", peer_addr);
+                    }
+                    result = proxying => if let Err(err) = result {
+                        debug!(target: "dlnaproxy", "Connection with {} ended: {}", peer_addr, err);
                     }
                 }
-            }
+
+                trace!(target: "dlnaproxy", "Closed connection with: {}", peer_addr);
+            });
         }
+
+        debug!(target: "dlnaproxy", "Stopped proxying TCP connections to {}.", origin);
     }
 }
 
-fn handle_conn(lhs_stream: TcpStream, rhs_stream: TcpStream) {
-    let peer_addr = lhs_stream.peer_addr().unwrap();
-
-    let lhs_arc = Arc::new(lhs_stream);
-    let rhs_arc = Arc::new(rhs_stream);
+/// Pipes bytes both ways, each direction being shut down once the other side is done writing.
+async fn handle_conn(mut client: TcpStream, origin: SocketAddr) -> io::Result<()> {
+    let mut upstream = TcpStream::connect(origin).await?;
 
-    let (mut lhs_tx, mut lhs_rx) = (lhs_arc.try_clone().unwrap(), lhs_arc.try_clone().unwrap());
-    let (mut rhs_tx, mut rhs_rx) = (rhs_arc.try_clone().unwrap(), rhs_arc.try_clone().unwrap());
+    let (sent, received) = io::copy_bidirectional(&mut client, &mut upstream).await?;
 
-    let connections = vec![
-        thread::spawn(move || io::copy(&mut lhs_tx, &mut rhs_rx).unwrap()),
-        thread::spawn(move || io::copy(&mut rhs_tx, &mut lhs_rx).unwrap()),
-    ];
-
-    for t in connections {
-        t.join().unwrap();
-    }
+    trace!(target: "dlnaproxy", "Relayed {} bytes to {} and {} bytes back.", sent, origin, received);
 
-    trace!(target: "dlnaproxy", "Closed connection with: {}", peer_addr);
+    Ok(())
 }